@@ -14,7 +14,7 @@ use nx::diag::log;
 
 use core::panic;
 
-#[no_mangle]
+#[unsafe(no_mangle)]
 pub fn initialize_heap(hbl_heap: util::PointerAndSize) -> util::PointerAndSize {
     if hbl_heap.is_valid() {
         hbl_heap
@@ -26,7 +26,7 @@ pub fn initialize_heap(hbl_heap: util::PointerAndSize) -> util::PointerAndSize {
     }
 }
 
-#[no_mangle]
+#[unsafe(no_mangle)]
 pub fn main() -> Result<()> {
     diag_log!(log::lm::LmLogger { log::LogSeverity::Trace, false } => "Hello world!");
 
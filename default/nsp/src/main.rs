@@ -18,14 +18,12 @@ use core::panic;
 const CUSTOM_HEAP_LEN: usize = 0x20000;
 static mut CUSTOM_HEAP: [u8; CUSTOM_HEAP_LEN] = [0; CUSTOM_HEAP_LEN];
 
-#[no_mangle]
+#[unsafe(no_mangle)]
 pub fn initialize_heap(_hbl_heap: util::PointerAndSize) -> util::PointerAndSize {
-    unsafe {
-        util::PointerAndSize::new(CUSTOM_HEAP.as_mut_ptr(), CUSTOM_HEAP.len())
-    }
+    util::PointerAndSize::new((&raw mut CUSTOM_HEAP).cast(), CUSTOM_HEAP_LEN)
 }
 
-#[no_mangle]
+#[unsafe(no_mangle)]
 pub fn main() -> Result<()> {
     diag_log!(log::lm::LmLogger { log::LogSeverity::Trace, false } => "Hello world!");
 
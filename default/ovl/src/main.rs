@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate nx;
+
+extern crate alloc;
+
+use nx::result::*;
+use nx::util;
+use nx::svc;
+use nx::diag::abort;
+use nx::diag::log;
+
+use core::panic;
+
+// Overlays run alongside the foreground application, so the overlay loader (like nx-ovlloader)
+// only provides them a small heap, which must be used as is
+#[unsafe(no_mangle)]
+pub fn initialize_heap(hbl_heap: util::PointerAndSize) -> util::PointerAndSize {
+    hbl_heap
+}
+
+#[unsafe(no_mangle)]
+pub fn main() -> Result<()> {
+    diag_log!(log::lm::LmLogger { log::LogSeverity::Trace, false } => "Hello world from an overlay!");
+
+    // The overlay loader (like Tesla's one) shows/hides the overlay on its button combo, and the
+    // overlay is expected to keep running on its own loop: this is where its UI would be drawn
+    loop {
+        // Sleep 10ms (aka 10'000'000 ns)
+        svc::sleep_thread(10_000_000)?;
+    }
+}
+
+#[panic_handler]
+fn panic_handler(info: &panic::PanicInfo) -> ! {
+    util::simple_panic_handler::<log::lm::LmLogger>(info, abort::AbortLevel::FatalThrow())
+}
\ No newline at end of file
@@ -0,0 +1,191 @@
+//! Remote RomFs assets.
+//!
+//! Assets declared in the NRO metadata are downloaded (with `curl`) into a cache directory inside
+//! the target directory, and verified against their SHA-256 checksum, so large binary assets don't
+//! need to be committed along with the project.
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    build::{io_error, BuildError},
+    log_file::log_println,
+};
+
+/// A remote asset to embed in the RomFs.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteAsset {
+    /// The URL to download the asset from.
+    pub url: String,
+    /// The path of the asset inside the RomFs.
+    pub path: String,
+    /// The expected SHA-256 checksum of the asset, as a hex string.
+    pub sha256: String,
+}
+
+impl RemoteAsset {
+    /// Get the absolute path of the asset inside the RomFs.
+    ///
+    /// Paths with `..` components are rejected, so every asset stays inside the RomFs.
+    pub fn romfs_path(&self) -> Result<String, BuildError> {
+        let path = self.path.trim_start_matches('/');
+        if path.is_empty() || path.split(['/', '\\']).any(|component| component == "..") {
+            return Err(BuildError::InvalidMetadataValue {
+                field: "romfs.fetch",
+                message: format!("invalid RomFs path `{}` for {}", self.path, self.url),
+            });
+        }
+        Ok(format!("/{}", path))
+    }
+}
+
+/// The cache where remote assets are stored.
+pub struct AssetCache {
+    /// The cache directory.
+    pub dir: PathBuf,
+    /// Whether missing (or outdated) assets can be downloaded.
+    pub download: bool,
+}
+
+impl AssetCache {
+    /// Get the path of the cached copy of `asset`, downloading it first if needed (and allowed).
+    ///
+    /// Fails if the asset can't be fetched or its checksum doesn't match.
+    pub fn fetch(&self, asset: &RemoteAsset) -> Result<PathBuf, BuildError> {
+        let sha256 = asset.sha256.trim().to_ascii_lowercase();
+        let cached_path = self.dir.join(&sha256);
+
+        // Skip the download if the cached copy is already the expected one
+        if file_sha256(&cached_path).as_deref() == Some(sha256.as_str()) {
+            return Ok(cached_path);
+        }
+        if !self.download {
+            return Err(BuildError::AssetNotCached {
+                url: asset.url.clone(),
+            });
+        }
+
+        log_println!("Fetching {}...", asset.url);
+        std::fs::create_dir_all(&self.dir).map_err(io_error(&self.dir))?;
+        let download_path = cached_path.with_extension("part");
+        let status = Command::new("curl")
+            .args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--output",
+            ])
+            .arg(&download_path)
+            .arg(&asset.url)
+            .status()
+            .map_err(|source| BuildError::Command {
+                program: PathBuf::from("curl"),
+                source,
+            })?;
+        if !status.success() {
+            return Err(BuildError::AssetDownloadFailed {
+                url: asset.url.clone(),
+                status,
+            });
+        }
+
+        let actual_sha256 = file_sha256(&download_path).unwrap_or_default();
+        if actual_sha256 != sha256 {
+            let _ = std::fs::remove_file(&download_path);
+            return Err(BuildError::AssetChecksumMismatch {
+                url: asset.url.clone(),
+                expected: sha256,
+                actual: actual_sha256,
+            });
+        }
+
+        std::fs::rename(&download_path, &cached_path).map_err(io_error(&cached_path))?;
+        Ok(cached_path)
+    }
+}
+
+/// Compute the SHA-256 checksum of a file as a lowercase hex string, if it can be read.
+fn file_sha256(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    let digest = Sha256::digest(&data);
+    Some(digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_asset(path: &str, sha256: &str) -> RemoteAsset {
+        RemoteAsset {
+            url: String::from("https://example.com/asset.bin"),
+            path: path.to_string(),
+            sha256: sha256.to_string(),
+        }
+    }
+
+    #[test]
+    fn asset_romfs_path() {
+        assert_eq!(
+            test_asset("data/asset.bin", "").romfs_path().unwrap(),
+            "/data/asset.bin"
+        );
+        assert_eq!(
+            test_asset("/asset.bin", "").romfs_path().unwrap(),
+            "/asset.bin"
+        );
+        for path in [
+            "",
+            "/",
+            "../asset.bin",
+            "data/../../asset.bin",
+            "data\\..\\asset.bin",
+        ] {
+            assert!(
+                matches!(
+                    test_asset(path, "").romfs_path(),
+                    Err(BuildError::InvalidMetadataValue { .. })
+                ),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn cached_asset_fetch() {
+        let dir = std::env::temp_dir().join(format!("cargo-nx-test-assets-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = AssetCache {
+            dir: dir.clone(),
+            download: false,
+        };
+
+        // The SHA-256 checksum of `hello`
+        let sha256 = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+        assert!(matches!(
+            cache.fetch(&test_asset("asset.bin", sha256)),
+            Err(BuildError::AssetNotCached { .. })
+        ));
+
+        // A cached copy with the expected checksum is used without downloading it
+        std::fs::create_dir_all(&dir).unwrap();
+        let cached_path = dir.join(sha256.to_ascii_lowercase());
+        std::fs::write(&cached_path, "hello").unwrap();
+        assert_eq!(
+            cache.fetch(&test_asset("asset.bin", sha256)).unwrap(),
+            cached_path
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
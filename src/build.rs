@@ -1,11 +1,15 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fs::{File, OpenOptions},
-    io::BufReader,
+    io::{self, BufReader, Read, Write},
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant, SystemTime},
 };
 
-use cargo_metadata::{Artifact, Message, MetadataCommand, Package};
+use cargo_metadata::{
+    diagnostic::DiagnosticLevel, Artifact, Message, Metadata, MetadataCommand, Package,
+};
 use linkle::format::{
     nacp::Nacp,
     npdm::{AcidBehavior, Npdm},
@@ -14,49 +18,792 @@ use linkle::format::{
     romfs::RomFs,
 };
 
+use crate::{
+    assets::{AssetCache, RemoteAsset},
+    config::{self, Config},
+    kip::{self, KipMetadata},
+    link,
+    log_file::{self, log_eprintln, log_println},
+    npdm::{self, NpdmFile},
+    nro,
+    nso::{self, Module},
+    size::{format_size, ByteSize},
+};
+
 /// The default target triple to use when building.
-const DEFAULT_TARGET_TRIPLE: &str = "aarch64-nintendo-switch-freestanding";
+pub const DEFAULT_TARGET_TRIPLE: &str = "aarch64-nintendo-switch-freestanding";
 
 /// The default icon to use when building an NRO.
-const DEFAULT_NRO_ICON: &[u8] = include_bytes!("../default/nro/default_icon.jpg");
+pub const DEFAULT_NRO_ICON: &[u8] = include_bytes!("../default/nro/default_icon.jpg");
+
+/// The interval between two scans of the files watched with `--watch`.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The time without further changes after which a `--watch` rebuild starts, so a burst of changes
+/// (like saving several files at once) only triggers one.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
 
 /// The `build` subcommand CLI arguments.
-#[derive(clap::Args)]
+#[derive(clap::Args, Clone)]
 pub struct Args {
     /// Builds using the release profile.
-    #[arg(short, long)]
+    #[arg(short, long, overrides_with = "no_release")]
     pub release: bool,
+    /// Builds using the dev profile, even if the configuration file sets `release`.
+    #[arg(long, overrides_with = "release")]
+    pub no_release: bool,
     /// The path to the project to build.
     #[arg(short, long, default_value = ".", value_name = "DIR", value_parser)]
     pub path: PathBuf,
+    /// The workspace package to build [default: the packages with nx format metadata, or all of
+    /// them if none has any].
+    #[arg(long, value_name = "PKG")]
+    pub package: Option<String>,
     /// The custom target triple to use, if any.
     #[arg(short, long)]
     pub target: Option<String>,
     /// Displays extra information during the build process.
     #[arg(short, long)]
     pub verbose: bool,
+    /// The number of parallel jobs of the cargo build [default: the number of CPUs].
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<u32>,
+    /// The features to activate, as a comma or space separated list.
+    /// Can be specified multiple times.
+    #[arg(long, value_name = "FEATURES")]
+    pub features: Vec<String>,
+    /// Activates all the available features.
+    #[arg(long)]
+    pub all_features: bool,
+    /// Doesn't activate the `default` feature.
+    #[arg(long)]
+    pub no_default_features: bool,
+    /// Overrides whether debug assertions are enabled for the selected profile.
+    #[arg(long, value_enum, value_name = "on|off")]
+    pub debug_assertions: Option<Toggle>,
+    /// Overrides whether overflow checks are enabled for the selected profile.
+    #[arg(long, value_enum, value_name = "on|off")]
+    pub overflow_checks: Option<Toggle>,
+    /// Overrides the panic strategy of the selected profile.
+    /// `unwind` requires the target and the `build-std` crates to support unwinding.
+    #[arg(long, value_enum, value_name = "abort|unwind")]
+    pub panic: Option<PanicStrategy>,
+    /// Overrides the format of the specified package, instead of the one of its metadata.
+    /// Can be specified multiple times.
+    #[arg(long, value_name = "PKG=nro|nsp|kip", value_parser = parse_format_override)]
+    pub format_for: Vec<(String, Format)>,
+    /// Writes the generated NRO/NSP/KIP files to this directory instead of next to the ELF.
+    #[arg(long, value_name = "DIR")]
+    pub out_dir: Option<PathBuf>,
+    /// Prints a summary of the generated NPDM of NSP packages (a hex dump too, with `--verbose`).
+    #[arg(long)]
+    pub dump_npdm: bool,
+    /// Fails the build of NRO packages without a configured icon, instead of embedding the default
+    /// one.
+    #[arg(long)]
+    pub require_icon: bool,
+    /// Compresses the NSO segments of NSP packages, overriding their `compress` metadata.
+    #[arg(long, overrides_with = "no_compress")]
+    pub compress: bool,
+    /// Leaves the NSO segments of NSP packages uncompressed, overriding their `compress` metadata.
+    #[arg(long, overrides_with = "compress")]
+    pub no_compress: bool,
+    /// Only generates the NACP (`control.nacp`) of an NRO package, without generating the NRO.
+    #[arg(long)]
+    pub nacp_only: bool,
+    /// Lists the workspace packages with their detected format and configured assets, without
+    /// building anything.
+    #[arg(long)]
+    pub list_packages: bool,
+    /// The cargo-compatible executable to invoke (`NX_CARGO` is used if not specified).
+    #[arg(long, value_name = "PATH")]
+    pub cargo_path: Option<PathBuf>,
+    /// The cargo subcommand to invoke instead of `build`.
+    /// It must emit cargo's JSON artifact messages.
+    #[arg(long, value_name = "NAME", default_value = "build")]
+    pub cargo_subcommand: String,
+    /// Prints how long each packaging step took after the build.
+    #[arg(long)]
+    pub timings: bool,
+    /// Writes a JSON manifest of the generated artifacts to the specified file.
+    #[arg(long, value_name = "FILE")]
+    pub json_artifacts_to: Option<PathBuf>,
+    /// Downloads the remote RomFs assets declared in the NRO metadata which aren't cached yet.
+    #[arg(long)]
+    pub offline_assets: bool,
+    /// Forwards cargo's JSON messages to stdout unchanged (for IDE tooling), instead of printing
+    /// the rendered compiler messages. The NRO/NSP files are still generated, and the status
+    /// messages are printed to stderr.
+    #[arg(long, conflicts_with = "link_after")]
+    pub passthrough_json: bool,
+    /// Builds with the minimal dependency versions allowed by the manifests (requires nightly).
+    /// The existing `Cargo.lock` is restored afterwards.
+    #[arg(long)]
+    pub minimal_versions: bool,
+    /// Also writes the build output (status messages, compiler diagnostics and errors) to the
+    /// specified file, truncating it first. It's ignored with `--dry-run`.
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Appends to the `--log-file` instead of truncating it.
+    #[arg(long, requires = "log_file")]
+    pub log_append: bool,
+    /// Fails the build on validation warnings, like an NPDM program ID that differs from the one
+    /// declared in the package metadata.
+    #[arg(long)]
+    pub strict: bool,
+    /// Generates the NRO/NSP from a copy of the ELF stripped of its symbols (with `llvm-strip`),
+    /// keeping the unstripped ELF for debugging tools like `addr2line`.
+    #[arg(long)]
+    pub strip: bool,
+    /// Keeps rebuilding the project when its sources (`src/`, the RomFs directories or the
+    /// manifest) change, until Ctrl+C.
+    #[arg(long)]
+    pub watch: bool,
+    /// Sends the NRO to a discovered netloader server after each successful `--watch` build.
+    #[arg(long, requires = "watch")]
+    pub link_after: bool,
+    /// Resolves and validates everything without building: prints the cargo command that would be
+    /// run and the format of each package, and checks that the configured paths exist.
+    #[arg(long, conflicts_with = "watch")]
+    pub dry_run: bool,
+    /// Extra arguments to pass verbatim to the cargo invocation (after a `--` separator).
+    #[arg(last = true, value_name = "CARGO_ARGS")]
+    pub cargo_args: Vec<String>,
 }
 
-/// Handle the `build` subcommand.
-pub fn handle_subcommand(args: Args) {
-    let metadata = MetadataCommand::new()
-        .manifest_path(args.path.join("Cargo.toml"))
+/// An on/off toggle for profile settings.
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+/// The output format of a package.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum Format {
+    Nro,
+    Nsp,
+    Kip,
+}
+
+fn parse_format_override(value: &str) -> Result<(String, Format), String> {
+    let (package_name, format) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `<PKG>=<FORMAT>`, got `{}`", value))?;
+    let format = <Format as clap::ValueEnum>::from_str(format, true)?;
+    Ok((package_name.to_string(), format))
+}
+
+/// Read the metadata of the workspace in the project directory, with the workspace nx metadata
+/// merged (see [`merge_workspace_metadata`]).
+///
+/// cargo is run from the project directory, as the build is, so the target directory it reports
+/// is the one configured for the project (`build.target-dir` in its `.cargo/config.toml`).
+pub fn read_metadata(project_dir: &Path) -> Result<Metadata, cargo_metadata::Error> {
+    let project_dir = std::env::current_dir()?.join(project_dir);
+    let mut metadata = MetadataCommand::new()
+        .manifest_path(project_dir.join("Cargo.toml"))
+        .current_dir(&project_dir)
         .no_deps()
-        .exec()
-        .unwrap();
+        .exec()?;
+    merge_workspace_metadata(&mut metadata);
+    Ok(metadata)
+}
+
+/// The keys of the nx metadata format tables.
+const FORMAT_KEYS: &[&str] = &["nro", "nsp", "kip"];
+
+/// Merge the workspace nx metadata (`[workspace.metadata.nx]`) under the one of each package with
+/// nx metadata, the package values taking precedence.
+///
+/// The format tables are only merged into packages already declaring that format, so the shared
+/// defaults don't make every package (like libraries) build as that format.
+fn merge_workspace_metadata(metadata: &mut Metadata) {
+    let Some(workspace_nx) = metadata
+        .workspace_metadata
+        .get("nx")
+        .and_then(|nx| nx.as_object())
+        .cloned()
+    else {
+        return;
+    };
+
+    for package in &mut metadata.packages {
+        let Some(package_nx) = package
+            .metadata
+            .get_mut("nx")
+            .and_then(|nx| nx.as_object_mut())
+        else {
+            continue;
+        };
+        for (key, workspace_value) in &workspace_nx {
+            match package_nx.get_mut(key) {
+                Some(package_value) => merge_json(package_value, workspace_value),
+                None if !FORMAT_KEYS.contains(&key.as_str()) => {
+                    package_nx.insert(key.clone(), workspace_value.clone());
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+/// Recursively merge the tables of `defaults` into `value`, keeping the existing values of `value`.
+fn merge_json(value: &mut serde_json::Value, defaults: &serde_json::Value) {
+    if let (Some(table), Some(defaults)) = (value.as_object_mut(), defaults.as_object()) {
+        for (key, default) in defaults {
+            match table.get_mut(key) {
+                Some(value) => merge_json(value, default),
+                None => {
+                    table.insert(key.clone(), default.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Detect the output format of a package from its metadata, if any.
+pub fn detect_format(package: &Package) -> Result<Option<Format>, BuildError> {
+    let formats: Vec<Format> = [
+        ("/nx/nro", Format::Nro),
+        ("/nx/nsp", Format::Nsp),
+        ("/nx/kip", Format::Kip),
+    ]
+    .into_iter()
+    .filter(|(pointer, _)| package.metadata.pointer(pointer).is_some())
+    .map(|(_, format)| format)
+    .collect();
+    match formats[..] {
+        [] => Ok(None),
+        [format] => Ok(Some(format)),
+        _ => Err(BuildError::MultipleFormats {
+            package: package.name.clone(),
+        }),
+    }
+}
 
-    let metadata_v = &metadata.packages[0].metadata;
+/// Get the target triple (or target spec file) declared in the nx metadata of a package
+/// (`/nx/target`), if any.
+fn metadata_target(package: &Package) -> Option<&str> {
+    package.metadata.pointer("/nx/target")?.as_str()
+}
 
-    let is_nsp = metadata_v.pointer("/nx/nsp").is_some();
-    let is_nro = metadata_v.pointer("/nx/nro").is_some();
-    if is_nsp && is_nro {
-        panic!("Error: multiple target formats are not yet supported...");
-    } else if is_nsp {
-        println!("Building and generating NSP...");
-    } else if is_nro {
-        println!("Building and generating NRO...");
+/// Select the packages built without an explicit package: the ones with a format, since
+/// library-only workspace members aren't worth building, or every package if none has one.
+///
+/// The first selected package is the main one.
+pub fn default_packages(
+    packages: &[Package],
+    package_format: impl Fn(&Package) -> Result<Option<Format>, BuildError>,
+) -> Result<Vec<&Package>, BuildError> {
+    let mut nx_packages = Vec::new();
+    for package in packages {
+        if package_format(package)?.is_some() {
+            nx_packages.push(package);
+        }
+    }
+    if nx_packages.is_empty() {
+        Ok(packages.iter().collect())
     } else {
-        println!("Building...");
+        Ok(nx_packages)
+    }
+}
+
+/// Resolve the target of the main package, returning the value to pass to cargo and the target
+/// triple name (see [`normalize_target`]).
+///
+/// A target from the metadata (relative to its package) is only overridden by `--target`, and
+/// takes precedence over the configured one (relative to the project directory, like `--target`).
+pub fn resolve_target(
+    target: Option<&str>,
+    main_package: &Package,
+    config_target: Option<&str>,
+    project_dir: &Path,
+) -> (String, String) {
+    match (target, metadata_target(main_package)) {
+        (None, Some(package_target)) => {
+            normalize_target(package_target, main_package.manifest_path.parent().unwrap())
+        }
+        (target, _) => normalize_target(
+            target.or(config_target).unwrap_or(DEFAULT_TARGET_TRIPLE),
+            project_dir,
+        ),
+    }
+}
+
+/// Normalize a `--target` value ending in `.json`, returning the value to pass to cargo and the
+/// target triple name (used for the output directories).
+///
+/// If the value is the path to a target spec file (relative to `project_dir`), cargo is given its
+/// absolute path, since it builds from `project_dir`. Otherwise the extension was likely included
+/// by mistake, so the file stem is used as a triple looked up in `RUST_TARGET_PATH`. Both cases
+/// name the target after the file stem, like cargo does.
+pub fn normalize_target(target: &str, project_dir: &Path) -> (String, String) {
+    let spec_path = Path::new(target);
+    if spec_path.extension().map_or(true, |ext| ext != "json") {
+        return (target.to_string(), target.to_string());
+    }
+
+    let triple = spec_path
+        .file_stem()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    match project_dir.join(spec_path).canonicalize() {
+        Ok(spec_path) if spec_path.is_file() => (spec_path.to_string_lossy().into_owned(), triple),
+        _ => (triple.clone(), triple),
+    }
+}
+
+/// Print a table of the packages with their format and whether their assets are configured.
+fn print_package_list(
+    packages: &[&Package],
+    package_format: impl Fn(&Package) -> Result<Option<Format>, BuildError>,
+) -> Result<(), BuildError> {
+    let name_width = packages
+        .iter()
+        .map(|package| package.name.len())
+        .chain(["PACKAGE".len()])
+        .max()
+        .unwrap();
+    log_println!(
+        "{:<name_width$}  {:<6}  {:<5}  {:<5}  NPDM",
+        "PACKAGE",
+        "FORMAT",
+        "ROMFS",
+        "ICON"
+    );
+
+    for package in packages {
+        let format = package_format(package)?;
+        let has = |pointer: &str| {
+            if package.metadata.pointer(pointer).is_some() {
+                "yes"
+            } else {
+                "no"
+            }
+        };
+        let (format, romfs, icon, npdm) = match format {
+            Some(Format::Nro) => {
+                let icon = if package.metadata.pointer("/nx/nro/icons").is_some() {
+                    "yes"
+                } else {
+                    has("/nx/nro/icon")
+                };
+                ("nro", has("/nx/nro/romfs"), icon, "-")
+            }
+            Some(Format::Nsp) => {
+                let npdm = if package.metadata.pointer("/nx/nsp/npdm_json").is_some() {
+                    "yes"
+                } else {
+                    has("/nx/nsp/npdm")
+                };
+                ("nsp", "-", "-", npdm)
+            }
+            Some(Format::Kip) => ("kip", "-", "-", "-"),
+            None => ("none", "-", "-", "-"),
+        };
+        log_println!(
+            "{:<name_width$}  {:<6}  {:<5}  {:<5}  {}",
+            package.name,
+            format,
+            romfs,
+            icon,
+            npdm
+        );
+    }
+    Ok(())
+}
+
+/// A panic strategy for profile settings.
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum PanicStrategy {
+    Abort,
+    Unwind,
+}
+
+impl PanicStrategy {
+    fn as_str(self) -> &'static str {
+        match self {
+            PanicStrategy::Abort => "abort",
+            PanicStrategy::Unwind => "unwind",
+        }
+    }
+}
+
+impl Toggle {
+    fn as_bool(self) -> bool {
+        matches!(self, Toggle::On)
+    }
+}
+
+/// An error that makes the build fail.
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    /// The package metadata couldn't be read (like when there is no `Cargo.toml`).
+    #[error("failed to read the package metadata: {0}")]
+    Metadata(#[from] cargo_metadata::Error),
+
+    /// A package has metadata for more than one of the NRO, NSP and KIP formats.
+    #[error("package `{package}` has metadata for more than one of the NRO, NSP and KIP formats, but multiple target formats are not yet supported")]
+    MultipleFormats { package: String },
+
+    /// An NRO icon isn't a JPEG of the expected dimensions.
+    #[error("invalid icon {}: {message}", path.display())]
+    InvalidIcon { path: PathBuf, message: String },
+
+    /// The target needs `build-std`, which the active stable toolchain can't do.
+    #[error("the {target} target has no prebuilt standard library, which needs to be built with `build-std`, but it requires a nightly toolchain and the active one is {release}: run `rustup toolchain install nightly --component rust-src`, then `rustup override set nightly` in the project directory (or pin it in a `rust-toolchain.toml`)")]
+    NightlyRequired { release: String, target: String },
+
+    /// The target needs `build-std`, which requires the missing `rust-src` component.
+    #[error("the {target} target has no prebuilt standard library, which needs to be built with `build-std`, but the rust-src component isn't installed: run `rustup component add rust-src`")]
+    RustSrcMissing { target: String },
+
+    /// No icon is configured for an NRO which requires one.
+    #[error("no icon is configured for the NRO (with the `icon` or `icons` metadata, or an `icon-<profile>.jpg` file), and the default one isn't allowed by --require-icon or `require-icon`")]
+    MissingIcon,
+
+    /// `--package` refers to a package that isn't part of the workspace.
+    #[error("package `{0}` isn't part of the workspace")]
+    UnknownPackage(String),
+
+    /// `--nacp-only` was specified for a package without NRO metadata.
+    #[error("--nacp-only requires a package with NRO metadata")]
+    NacpOnlyRequiresNro,
+
+    /// The nx metadata of a package is malformed.
+    #[error("invalid `package.metadata.nx.{format}` of package `{package}`: {source}")]
+    InvalidMetadata {
+        package: String,
+        format: &'static str,
+        source: serde_json::Error,
+    },
+
+    /// A value of the nx metadata is out of range or inconsistent.
+    #[error("invalid `{field}` metadata: {message}")]
+    InvalidMetadataValue {
+        field: &'static str,
+        message: String,
+    },
+
+    /// A required part of the nx metadata is missing.
+    #[error("no `{0}` specified in the package metadata")]
+    MissingMetadata(&'static str),
+
+    /// The NPDM program ID differs from the one expected by the package metadata.
+    #[error("the NPDM program ID ({npdm_program_id:#018X}) differs from the {expected} ({expected_program_id:#018X})")]
+    ProgramIdMismatch {
+        npdm_program_id: u64,
+        expected: &'static str,
+        expected_program_id: u64,
+    },
+
+    /// A file or directory referenced by the metadata doesn't exist.
+    #[error("the {what} {} doesn't exist", path.display())]
+    NotFound { what: &'static str, path: PathBuf },
+
+    /// A command (like cargo) couldn't be run.
+    #[error("failed to run `{}`: {source}", program.display())]
+    Command { program: PathBuf, source: io::Error },
+
+    /// The JSON messages of cargo couldn't be read.
+    #[error("failed to read the cargo output: {0}")]
+    CargoOutput(io::Error),
+
+    /// No ELF was generated for a target.
+    #[error("no ELF was generated for the {0} target")]
+    NoElf(String),
+
+    /// A file or directory couldn't be read or written.
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// A container (like the NPDM or the RomFs) couldn't be generated.
+    #[error("failed to generate the {what}: {message}")]
+    Generation { what: &'static str, message: String },
+
+    /// Cargo failed to update the lockfile to the minimal dependency versions.
+    #[error("failed to resolve the minimal dependency versions (is nightly in use?)")]
+    MinimalVersionsFailed { code: i32 },
+
+    /// Cargo failed to build the project.
+    #[error("the build failed{}", if *minimal_versions { " with the minimal dependency versions" } else { "" })]
+    CargoFailed { code: i32, minimal_versions: bool },
+
+    /// A remote RomFs asset isn't cached, and downloading it wasn't allowed.
+    #[error("the remote asset {url} isn't cached, build with --offline-assets to fetch it")]
+    AssetNotCached { url: String },
+
+    /// A remote RomFs asset couldn't be downloaded.
+    #[error("failed to fetch the remote asset {url} (curl {status})")]
+    AssetDownloadFailed { url: String, status: ExitStatus },
+
+    /// A downloaded remote RomFs asset doesn't have the expected checksum.
+    #[error("checksum mismatch for the remote asset {url} (expected {expected}, got {actual})")]
+    AssetChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+
+    /// A generated file is larger than its configured maximum output size.
+    #[error("{} exceeds the maximum output size ({} > {})", path.display(), format_size(*size), format_size(*max_size))]
+    OutputTooLarge {
+        path: PathBuf,
+        size: u64,
+        max_size: u64,
+    },
+}
+
+impl BuildError {
+    /// The exit code of the process when the build fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuildError::CargoFailed { code, .. } | BuildError::MinimalVersionsFailed { code } => {
+                *code
+            }
+            _ => 1,
+        }
+    }
+}
+
+/// Map an I/O error on `path` to a [`BuildError`].
+pub fn io_error(path: &Path) -> impl FnOnce(io::Error) -> BuildError + '_ {
+    move |source| BuildError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Handle the `build` subcommand.
+pub fn handle_subcommand(args: Args) -> Result<(), BuildError> {
+    init_output(&args)?;
+    if args.watch {
+        return watch(args);
+    }
+    build(args).map(|_| ())
+}
+
+/// Set up the output of the builds: redirect the status messages with `--passthrough-json`, and open
+/// the `--log-file`.
+///
+/// This must be done once before building, since `--watch` builds several times in the same log.
+pub fn init_output(args: &Args) -> Result<(), BuildError> {
+    // Stdout only carries the forwarded cargo JSON messages, for the tools parsing it
+    if args.passthrough_json {
+        log_file::redirect_stdout_to_stderr();
+    }
+    // No file is written by a dry run, not even the log file
+    if let Some(log_file_path) = args.log_file.as_ref().filter(|_| !args.dry_run) {
+        log_file::open(log_file_path, args.log_append).map_err(io_error(log_file_path))?;
+    }
+    Ok(())
+}
+
+/// Rebuild the project whenever the watched files change, sending the NRO to a netloader server
+/// after each successful build if `--link-after` is set.
+///
+/// Build failures are reported, then the next change is awaited.
+fn watch(args: Args) -> Result<(), BuildError> {
+    let watched_paths = watched_paths(&args.path)?;
+    let mut snapshot = snapshot_files(&watched_paths);
+    loop {
+        match build(args.clone()) {
+            Ok(artifacts) if args.link_after => {
+                match artifacts
+                    .iter()
+                    .find(|artifact| artifact.extension().is_some_and(|ext| ext == "nro"))
+                {
+                    Some(nro) => link::handle_subcommand(link::Args::for_file(nro, &args.path)),
+                    None => log_eprintln!("Warning: no NRO was generated, nothing to link"),
+                }
+            }
+            Ok(_) => (),
+            Err(err) => log_eprintln!("Error: {}", err),
+        }
+
+        log_println!("Watching for changes (press Ctrl+C to exit)...");
+        snapshot = wait_for_changes(&watched_paths, snapshot);
+        log_println!("Change detected, rebuilding...");
+    }
+}
+
+/// Get the paths watched with `--watch`: the `src/` directory, manifest and RomFs directories of
+/// each workspace package.
+fn watched_paths(project_dir: &Path) -> Result<Vec<PathBuf>, BuildError> {
+    let metadata = read_metadata(project_dir)?;
+
+    let mut paths = Vec::new();
+    for package in &metadata.packages {
+        let root = package.manifest_path.parent().unwrap();
+        paths.push(package.manifest_path.clone());
+        paths.push(root.join("src"));
+
+        let romfs = package
+            .metadata
+            .pointer("/nx/nro/romfs")
+            .and_then(|romfs| serde_json::from_value::<RomFsMetadata>(romfs.clone()).ok());
+        let romfs_dirs = match &romfs {
+            Some(RomFsMetadata::Dirs(dirs)) => dirs.as_slice(),
+            Some(RomFsMetadata::Table {
+                dir: Some(dirs), ..
+            }) => dirs.as_slice(),
+            Some(RomFsMetadata::Table { dir: None, .. }) | None => &[],
+        };
+        paths.extend(romfs_dirs.iter().map(|dir| root.join(dir)));
+    }
+    Ok(paths)
+}
+
+/// The modification time and size of each watched file.
+type FileSnapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+/// Take a snapshot of the files in the watched paths (recursively for directories).
+fn snapshot_files(paths: &[PathBuf]) -> FileSnapshot {
+    fn visit(path: &Path, snapshot: &mut FileSnapshot) {
+        let Ok(file_metadata) = std::fs::metadata(path) else {
+            return;
+        };
+        if file_metadata.is_dir() {
+            for entry in std::fs::read_dir(path).into_iter().flatten().flatten() {
+                visit(&entry.path(), snapshot);
+            }
+        } else {
+            snapshot.insert(
+                path.to_path_buf(),
+                (file_metadata.modified().ok(), file_metadata.len()),
+            );
+        }
+    }
+
+    let mut snapshot = FileSnapshot::new();
+    for path in paths {
+        visit(path, &mut snapshot);
+    }
+    snapshot
+}
+
+/// Wait until the watched files change and stop changing for [`WATCH_DEBOUNCE`], returning the new
+/// snapshot.
+fn wait_for_changes(paths: &[PathBuf], snapshot: FileSnapshot) -> FileSnapshot {
+    let mut current = snapshot.clone();
+    while current == snapshot {
+        std::thread::sleep(WATCH_POLL_INTERVAL);
+        current = snapshot_files(paths);
+    }
+
+    loop {
+        std::thread::sleep(WATCH_DEBOUNCE);
+        let next = snapshot_files(paths);
+        if next == current {
+            return current;
+        }
+        current = next;
+    }
+}
+
+/// Build the project, returning the paths of the generated NRO/NSP files.
+///
+/// The output must have been set up with [`init_output`] first.
+pub fn build(args: Args) -> Result<Vec<PathBuf>, BuildError> {
+    let started = Instant::now();
+
+    let metadata = read_metadata(&args.path)?;
+
+    let config = Config::load(&args.path).build;
+    let release = config::resolve_flag(args.release, args.no_release, config.release);
+
+    for (package_name, _) in &args.format_for {
+        if !metadata
+            .packages
+            .iter()
+            .any(|package| &package.name == package_name)
+        {
+            log_eprintln!(
+                "Warning: --format-for refers to an unknown package `{}`",
+                package_name
+            );
+        }
+    }
+    let package_format = |package: &Package| match args
+        .format_for
+        .iter()
+        .rev()
+        .find(|(package_name, _)| package_name == &package.name)
+    {
+        Some((_, format)) => Ok(Some(*format)),
+        None => detect_format(package),
+    };
+
+    if args.list_packages {
+        let packages: Vec<&Package> = metadata.packages.iter().collect();
+        return print_package_list(&packages, package_format).map(|()| Vec::new());
+    }
+
+    let selected_packages: Vec<&Package> = match &args.package {
+        Some(package_name) => vec![metadata
+            .packages
+            .iter()
+            .find(|package| &package.name == package_name)
+            .ok_or_else(|| BuildError::UnknownPackage(package_name.clone()))?],
+        None => default_packages(&metadata.packages, package_format)?,
+    };
+    if metadata.packages.len() > 1 {
+        let names: Vec<&str> = selected_packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        log_println!("Selected packages: {}", names.join(", "));
+    }
+    let main_package = selected_packages[0];
+
+    let package_name = &main_package.name;
+    let format = package_format(main_package)?;
+    if args.nacp_only {
+        if format != Some(Format::Nro) {
+            return Err(BuildError::NacpOnlyRequiresNro);
+        }
+        // The NACP defaults filled from the package aren't enough, one has to be declared
+        for package in &selected_packages {
+            if package_format(package)? == Some(Format::Nro)
+                && package.metadata.pointer("/nx/nro/nacp").is_none()
+            {
+                return Err(BuildError::MissingMetadata("nacp"));
+            }
+        }
+    }
+
+    match format {
+        Some(format) => {
+            let format_name = match format {
+                Format::Nro => "NRO",
+                Format::Nsp => "NSP",
+                Format::Kip => "KIP",
+            };
+            if args.nacp_only {
+                log_println!(
+                    "Detected {} format for package {}, generating NACP...",
+                    format_name,
+                    package_name
+                );
+            } else {
+                log_println!(
+                    "Detected {} format for package {}, building and generating {}...",
+                    format_name,
+                    package_name,
+                    format_name
+                );
+            }
+            warn_if_missing_nx_dependency(main_package, format_name);
+        }
+        None => log_println!(
+            "No nx format metadata found for package {}, building as a plain cargo target (no NRO/NSP/KIP will be produced)...",
+            package_name
+        ),
     }
 
     let rust_target_path = match std::env::var("RUST_TARGET_PATH") {
@@ -64,36 +811,212 @@ pub fn handle_subcommand(args: Args) {
         Err(_) => metadata.workspace_root.clone(),
     };
 
-    let target = args.target.as_deref().unwrap_or(DEFAULT_TARGET_TRIPLE);
+    let (target_arg, target) = resolve_target(
+        args.target.as_deref(),
+        main_package,
+        config.target.as_deref(),
+        &args.path,
+    );
+    let target = target.as_str();
     if args.verbose {
-        println!("Target triple: {}", target);
+        log_println!("Target triple: {}", target);
     }
 
+    // The NACP only depends on the metadata, so nothing is compiled for it
+    if args.nacp_only {
+        if args.dry_run {
+            log_println!("Dry run: the metadata is valid, nothing was written");
+            return Ok(Vec::new());
+        }
+        let out_dir = match &args.out_dir {
+            Some(out_dir) => out_dir.clone(),
+            None => metadata.target_directory.join(target).join(if release {
+                "release"
+            } else {
+                "debug"
+            }),
+        };
+        let built_artifacts = handle_nacp_only(
+            &selected_packages,
+            package_format,
+            &out_dir,
+            &Timings::new(started),
+        )?;
+        if let Some(manifest_path) = args.json_artifacts_to {
+            write_artifacts_manifest(&manifest_path, &built_artifacts)?;
+        }
+        return Ok(built_artifacts
+            .into_iter()
+            .map(|artifact| artifact.path)
+            .collect());
+    }
+    if !args.dry_run {
+        check_target_marker(&metadata.target_directory, target);
+    }
+    check_toolchain(&args.path, target)?;
+
     let build_target_path = rust_target_path.to_str().unwrap();
     if args.verbose {
-        println!("Build target path: {}", build_target_path);
+        log_println!("Build target path: {}", build_target_path);
     }
 
     let mut build_args: Vec<String> = vec![
-        String::from("build"),
-        format!("--target={}", target),
+        args.cargo_subcommand.clone(),
+        format!("--target={}", target_arg),
         String::from("--message-format=json-diagnostic-rendered-ansi"),
     ];
-    if args.release {
+    if release {
         build_args.push(String::from("--release"));
     }
+    // Every generated file is derived from the target directory of the metadata, so make sure cargo
+    // builds to the same one
+    build_args.push(format!(
+        "--target-dir={}",
+        metadata.target_directory.to_string_lossy()
+    ));
+    if selected_packages.len() < metadata.packages.len() {
+        for package in &selected_packages {
+            build_args.push(format!("--package={}", package.name));
+        }
+    }
 
-    #[allow(clippy::zombie_processes)] // TODO: Fix `spawned process is never waited` clippy warning
-    let mut command = Command::new("cargo")
-        .args(&build_args)
-        .stdout(Stdio::piped())
-        .env("RUST_TARGET_PATH", build_target_path)
-        .current_dir(&args.path)
-        .spawn()
-        .unwrap();
+    if let Some(jobs) = args.jobs {
+        build_args.push(format!("--jobs={}", jobs));
+    }
+
+    let features: Vec<&str> = args
+        .features
+        .iter()
+        .flat_map(|features| features.split(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|feature| !feature.is_empty())
+        .collect();
+    if args.all_features && !features.is_empty() {
+        log_eprintln!(
+            "Warning: --features has no effect with --all-features, as every feature is activated"
+        );
+    }
+    if !features.is_empty() {
+        build_args.push(format!("--features={}", features.join(",")));
+    }
+    if args.all_features {
+        build_args.push(String::from("--all-features"));
+    }
+    if args.no_default_features {
+        build_args.push(String::from("--no-default-features"));
+    }
+
+    let profile = if release { "release" } else { "dev" };
+    if let Some(debug_assertions) = args.debug_assertions {
+        build_args.push(format!(
+            "--config=profile.{}.debug-assertions={}",
+            profile,
+            debug_assertions.as_bool()
+        ));
+    }
+    if let Some(overflow_checks) = args.overflow_checks {
+        build_args.push(format!(
+            "--config=profile.{}.overflow-checks={}",
+            profile,
+            overflow_checks.as_bool()
+        ));
+    }
+    if let Some(panic) = args.panic {
+        build_args.push(format!(
+            "--config=profile.{}.panic=\"{}\"",
+            profile,
+            panic.as_str()
+        ));
+    }
+    if let Some(ld_script) = selected_linker_script(&selected_packages, package_format)? {
+        // Cargo ignores `build.rustflags` if the target has its own rustflags, which the arrays of
+        // `--config` are appended to
+        build_args.push(format!(
+            "--config=target.{}.rustflags=[\"-C\", {}]",
+            serde_json::to_string(target).unwrap(),
+            serde_json::to_string(&format!("link-arg=-T{}", ld_script.to_string_lossy())).unwrap()
+        ));
+    }
+
+    let cargo = args
+        .cargo_path
+        .clone()
+        .or_else(|| std::env::var_os("NX_CARGO").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("cargo"));
+
+    // Resolve the dependencies to their minimal versions, keeping the current lockfile around
+    let lockfile_backup = if args.minimal_versions && args.dry_run {
+        build_args.push(String::from("-Zminimal-versions"));
+        None
+    } else if args.minimal_versions {
+        build_args.push(String::from("-Zminimal-versions"));
+        Some(resolve_minimal_versions(
+            &cargo,
+            &args.path,
+            &metadata.workspace_root.join("Cargo.lock"),
+        )?)
+    } else {
+        None
+    };
+
+    build_args.extend(args.cargo_args.iter().cloned());
+
+    if args.verbose || args.dry_run {
+        let command_line: Vec<String> = std::iter::once(cargo.to_string_lossy().into_owned())
+            .chain(build_args.iter().cloned())
+            .map(|arg| shell_quote(&arg))
+            .collect();
+        log_println!(
+            "{}: RUST_TARGET_PATH={} {}",
+            if args.dry_run { "Would run" } else { "Running" },
+            shell_quote(build_target_path),
+            command_line.join(" ")
+        );
+    }
+
+    if args.dry_run {
+        print_package_list(&selected_packages, package_format)?;
+        for package in &selected_packages {
+            if let Some(format) = package_format(package)? {
+                check_metadata_paths(package, format)?;
+            }
+        }
+        log_println!("Dry run: the metadata is valid, nothing was built");
+        return Ok(Vec::new());
+    }
+
+    // Dropped (on any error) before the lockfile backup, so cargo is stopped before it's restored
+    let mut command = CargoProcess(
+        Command::new(&cargo)
+            .args(&build_args)
+            .stdout(Stdio::piped())
+            .env("RUST_TARGET_PATH", build_target_path)
+            .current_dir(&args.path)
+            .spawn()
+            .map_err(|source| BuildError::Command {
+                program: cargo.clone(),
+                source,
+            })?,
+    );
+
+    let out_dir = args.out_dir.as_deref();
+    let asset_cache = AssetCache {
+        dir: metadata.target_directory.join("cargo-nx").join("assets"),
+        download: args.offline_assets,
+    };
+    let mut timings = Timings::new(started);
+    let mut built_artifacts = Vec::new();
+    let mut compiler_errors = 0;
 
-    let reader = BufReader::new(command.stdout.take().unwrap());
-    for message in Message::parse_stream(reader) {
+    let stdout = command.0.stdout.take().unwrap();
+    let reader: Box<dyn Read> = if args.passthrough_json {
+        Box::new(TeeReader {
+            inner: stdout,
+            out: std::io::stdout(),
+        })
+    } else {
+        Box::new(stdout)
+    };
+    for message in Message::parse_stream(BufReader::new(reader)) {
         match message {
             Ok(Message::CompilerArtifact(ref artifact)) => {
                 if artifact.target.kind.contains(&"bin".into())
@@ -110,137 +1033,1624 @@ pub fn handle_subcommand(args: Args) {
 
                     let root = package.manifest_path.parent().unwrap();
 
-                    if is_nsp {
-                        let nsp_metadata: NspMetadata =
-                            serde_json::from_value(metadata_v.pointer("/nx/nsp").cloned().unwrap())
-                                .unwrap_or_default();
-                        handle_nsp_format(root, artifact, nsp_metadata);
-                    } else if is_nro {
-                        let nro_metadata: NroMetadata =
-                            serde_json::from_value(metadata_v.pointer("/nx/nro").cloned().unwrap())
-                                .unwrap_or_default();
-                        handle_nro_format(root, artifact, nro_metadata);
+                    let invalid_metadata = |format, source| BuildError::InvalidMetadata {
+                        package: package.name.clone(),
+                        format,
+                        source,
+                    };
+                    let format = package_format(package)?;
+                    let artifact = &match format {
+                        Some(_) if args.strip => strip_artifact(artifact, &args.path)?,
+                        _ => artifact.clone(),
+                    };
+                    let output = if format == Some(Format::Nsp) {
+                        check_nacp_program_id(root, &package.metadata)?;
+                        let mut nsp_metadata_v = package
+                            .metadata
+                            .pointer("/nx/nsp")
+                            .cloned()
+                            .unwrap_or_default();
+                        apply_npdm_settings(root, &mut nsp_metadata_v)?;
+                        let mut nsp_metadata: NspMetadata = serde_json::from_value(nsp_metadata_v)
+                            .map_err(|err| invalid_metadata("nsp", err))?;
+                        if args.compress || args.no_compress {
+                            nsp_metadata.compress = Some(args.compress);
+                        }
+                        Some(handle_nsp_format(
+                            root,
+                            artifact,
+                            out_dir,
+                            nsp_metadata,
+                            &args,
+                            &mut timings,
+                        )?)
+                    } else if format == Some(Format::Nro) {
+                        let mut nro_metadata = read_nro_metadata(package)?;
+                        if args.require_icon {
+                            nro_metadata.require_icon = Some(true);
+                        }
+                        Some(handle_nro_format(
+                            root,
+                            artifact,
+                            out_dir,
+                            nro_metadata,
+                            profile,
+                            &asset_cache,
+                            &mut timings,
+                        )?)
+                    } else if format == Some(Format::Kip) {
+                        let kip_metadata_v = package
+                            .metadata
+                            .pointer("/nx/kip")
+                            .cloned()
+                            .unwrap_or_default();
+                        let kip_metadata: KipMetadata = serde_json::from_value(kip_metadata_v)
+                            .map_err(|err| invalid_metadata("kip", err))?;
+                        Some(handle_kip_format(
+                            package,
+                            artifact,
+                            out_dir,
+                            kip_metadata,
+                            &mut timings,
+                        )?)
+                    } else {
+                        None
+                    };
+
+                    if let Some(output) = output {
+                        built_artifacts.push(BuiltArtifact::new(&package.name, output));
                     }
                 }
             }
             Ok(Message::CompilerMessage(msg)) => {
+                if matches!(
+                    msg.message.level,
+                    DiagnosticLevel::Error | DiagnosticLevel::Ice
+                ) {
+                    compiler_errors += 1;
+                }
+
+                if args.passthrough_json {
+                    continue;
+                }
                 if let Some(msg) = msg.message.rendered {
-                    println!("{}", msg);
+                    log_println!("{}", msg);
                 } else {
-                    println!("{:?}", msg);
+                    log_println!("{:?}", msg);
                 }
             }
             Ok(_) => (),
-            Err(err) => {
-                panic!("{:?}", err);
-            }
+            Err(err) => return Err(BuildError::CargoOutput(err)),
+        }
+    }
+
+    let status = command.0.wait().map_err(BuildError::CargoOutput)?;
+    drop(lockfile_backup);
+
+    // Fail if cargo did, or if any compiler error was reported even though it didn't
+    if !status.success() || compiler_errors > 0 {
+        return Err(BuildError::CargoFailed {
+            code: status.code().filter(|&code| code != 0).unwrap_or(1),
+            minimal_versions: args.minimal_versions,
+        });
+    }
+    if args.minimal_versions {
+        log_println!("The build succeeded with the minimal dependency versions");
+    }
+
+    if args.timings {
+        timings.print();
+    }
+
+    if let Some(manifest_path) = args.json_artifacts_to {
+        write_artifacts_manifest(&manifest_path, &built_artifacts)?;
+    }
+
+    Ok(built_artifacts
+        .into_iter()
+        .map(|artifact| artifact.path)
+        .collect())
+}
+
+/// An artifact generated by the build, as written to the JSON artifacts manifest.
+#[derive(Debug, serde::Serialize)]
+struct BuiltArtifact {
+    package: String,
+    format: String,
+    path: PathBuf,
+    size: u64,
+}
+
+impl BuiltArtifact {
+    fn new(package: &str, path: PathBuf) -> Self {
+        let format = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+        Self {
+            package: package.to_string(),
+            format,
+            path,
+            size,
         }
     }
 }
 
+/// Write the JSON artifacts manifest, atomically replacing the file if it exists.
+fn write_artifacts_manifest(path: &Path, artifacts: &[BuiltArtifact]) -> Result<(), BuildError> {
+    let manifest = serde_json::json!({ "artifacts": artifacts });
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, serde_json::to_string_pretty(&manifest).unwrap())
+        .map_err(io_error(&temp_path))?;
+    std::fs::rename(&temp_path, path).map_err(io_error(path))
+}
+
+/// The accumulated durations of the packaging steps, along with the start of the whole build.
+#[derive(Debug)]
+struct Timings {
+    started: Instant,
+    steps: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Run a packaging step, adding its duration to the step's total.
+    fn time<T>(&mut self, step: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+
+        match self.steps.iter_mut().find(|(name, _)| *name == step) {
+            Some((_, duration)) => *duration += elapsed,
+            None => self.steps.push((step, elapsed)),
+        }
+
+        result
+    }
+
+    fn print(&self) {
+        if self.steps.is_empty() {
+            return;
+        }
+
+        let total: Duration = self.steps.iter().map(|(_, duration)| *duration).sum();
+        log_println!("Packaging timings:");
+        for (step, duration) in &self.steps {
+            log_println!("  {:<20} {:>8.3}s", step, duration.as_secs_f64());
+        }
+        log_println!("  {:<20} {:>8.3}s", "Total", total.as_secs_f64());
+    }
+}
+
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 struct NspMetadata {
     npdm: Option<Npdm>,
     npdm_json: Option<String>,
+    acid: Option<AcidMetadata>,
+    max_output_size: Option<ByteSize>,
+    program_id: Option<String>,
+    compress: Option<bool>,
+}
+
+/// How the ACID of the generated NPDM is produced.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AcidMetadata {
+    /// Leave the ACID empty (unsigned).
+    #[default]
+    Empty,
+    /// Sign the ACID with the specified PEM private key file.
+    Sign(String),
+    /// Use the specified (already signed) ACID file.
+    Use(String),
 }
 
 #[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 struct NroMetadata {
-    romfs: Option<String>,
+    romfs: Option<RomFsMetadata>,
     icon: Option<String>,
+    icons: Option<HashMap<String, String>>,
     nacp: Option<Nacp>,
+    overlay: Option<bool>,
+    max_output_size: Option<ByteSize>,
+    #[serde(alias = "require-icon")]
+    require_icon: Option<bool>,
 }
 
-fn get_output_elf_path_as(artifact: &Artifact, extension: &str) -> PathBuf {
-    let mut elf = artifact.filenames[0].clone();
-    assert!(elf.set_extension(extension));
-    elf
+/// The RomFs of an NRO: either directories, or a table also declaring remote assets.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum RomFsMetadata {
+    Dirs(RomFsDirs),
+    Table {
+        dir: Option<RomFsDirs>,
+        #[serde(default)]
+        fetch: Vec<RemoteAsset>,
+    },
 }
 
-fn handle_nro_format(root: &Path, artifact: &Artifact, metadata: NroMetadata) {
-    let elf = artifact.filenames[0].clone();
-    let nro = get_output_elf_path_as(artifact, "nro");
+/// The RomFs source directories: a single one, or several merged in order.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum RomFsDirs {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl RomFsDirs {
+    fn as_slice(&self) -> &[String] {
+        match self {
+            RomFsDirs::Single(dir) => std::slice::from_ref(dir),
+            RomFsDirs::Multiple(dirs) => dirs,
+        }
+    }
+}
 
-    let romfs = metadata
-        .romfs
-        .as_ref()
-        .map(|romfs_dir| RomFs::from_directory(&root.join(romfs_dir)).unwrap());
-    let provided_icon = metadata
-        .icon
-        .as_ref()
-        .map(|icon_file| root.join(icon_file.clone()))
-        .map(|icon_path| icon_path.to_string_lossy().into_owned());
+/// Copy the contents of `src` into `dst`, overwriting any existing files.
+fn copy_dir_merging(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_merging(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
 
-    let icon: Option<String> = match provided_icon {
-        Some(icon) => Some(icon),
+/// Build a RomFs from several directories, later ones overriding earlier ones on path collision.
+///
+/// The directories are merged into `staging_dir` first, since a RomFs is built from a single one.
+fn romfs_from_directories(
+    root: &Path,
+    dirs: &[String],
+    staging_dir: &Path,
+) -> Result<RomFs, BuildError> {
+    let romfs_dir = match dirs {
+        [] => return Ok(RomFs::empty()),
+        [dir] => root.join(dir),
         _ => {
-            let temp_icon = get_output_elf_path_as(artifact, "jpg");
-            std::fs::write(temp_icon.clone(), DEFAULT_NRO_ICON)
-                .expect("Failed to save temporary default icon file");
+            if staging_dir.exists() {
+                std::fs::remove_dir_all(staging_dir).map_err(io_error(staging_dir))?;
+            }
+            for dir in dirs {
+                let dir = root.join(dir);
+                if !dir.is_dir() {
+                    return Err(BuildError::NotFound {
+                        what: "RomFs directory",
+                        path: dir,
+                    });
+                }
+                copy_dir_merging(&dir, staging_dir).map_err(io_error(staging_dir))?;
+            }
+            staging_dir.to_path_buf()
+        }
+    };
+    RomFs::from_directory(&romfs_dir).map_err(|err| BuildError::Generation {
+        what: "RomFs",
+        message: format!("{}: {}", romfs_dir.display(), err),
+    })
+}
+
+/// The extensions of the artifact files that aren't the ELF itself.
+///
+/// A `cdylib` crate can also be built as other crate types (like `rlib`), in which case all the
+/// generated files are listed in the artifact, in no particular order.
+const NON_ELF_EXTENSIONS: &[&str] = &["rlib", "rmeta", "a", "d"];
 
-            Some(temp_icon.to_string_lossy().into_owned())
+/// Get the ELF of a `bin` or `cdylib` artifact, from which the NRO or NSO is generated.
+fn get_artifact_elf(artifact: &Artifact) -> Result<&PathBuf, BuildError> {
+    artifact
+        .filenames
+        .iter()
+        .find(|path| {
+            path.extension().map_or(true, |ext| {
+                !NON_ELF_EXTENSIONS.contains(&&*ext.to_string_lossy())
+            })
+        })
+        .ok_or_else(|| BuildError::NoElf(artifact.target.name.clone()))
+}
+
+fn get_output_elf_path_as(artifact: &Artifact, extension: &str) -> Result<PathBuf, BuildError> {
+    let mut elf = get_artifact_elf(artifact)?.clone();
+    assert!(elf.set_extension(extension));
+    Ok(elf)
+}
+
+/// Get the path of a final generated file: next to the ELF, or inside `out_dir` if specified.
+fn get_output_path(
+    artifact: &Artifact,
+    out_dir: Option<&Path>,
+    file_name: &str,
+) -> Result<PathBuf, BuildError> {
+    match out_dir {
+        Some(out_dir) => {
+            std::fs::create_dir_all(out_dir).map_err(io_error(out_dir))?;
+            Ok(out_dir.join(file_name))
+        }
+        None => Ok(get_artifact_elf(artifact)?
+            .parent()
+            .unwrap()
+            .join(file_name)),
+    }
+}
+
+/// A reader echoing everything read from `inner` to `out`.
+struct TeeReader<R: Read, W: Write> {
+    inner: R,
+    out: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.out.write_all(&buf[..len])?;
+        self.out.flush()?;
+        Ok(len)
+    }
+}
+
+/// A running cargo process, killed if it's still running when dropped (like when the build fails
+/// while reading its output), so it's never left behind.
+struct CargoProcess(Child);
+
+impl Drop for CargoProcess {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+/// The contents of a `Cargo.lock` file, restored when dropped after building with the minimal
+/// versions (even if the build fails).
+struct LockfileBackup {
+    path: PathBuf,
+    contents: Option<Vec<u8>>,
+}
+
+impl Drop for LockfileBackup {
+    /// Restore the lockfile, removing it if it didn't exist before.
+    fn drop(&mut self) {
+        let result = match self.contents.take() {
+            Some(contents) => std::fs::write(&self.path, contents),
+            None => std::fs::remove_file(&self.path),
+        };
+        if let Err(err) = result {
+            log_eprintln!(
+                "Warning: failed to restore {}: {}",
+                self.path.to_string_lossy(),
+                err
+            );
         }
+    }
+}
+
+/// Update the lockfile at `lockfile` to the minimal dependency versions, returning a backup of the
+/// previous one.
+fn resolve_minimal_versions(
+    cargo: &Path,
+    project_dir: &Path,
+    lockfile: &Path,
+) -> Result<LockfileBackup, BuildError> {
+    let backup = LockfileBackup {
+        path: lockfile.to_path_buf(),
+        contents: std::fs::read(lockfile).ok(),
     };
 
-    Nxo::from_elf(elf.to_str().unwrap())
-        .unwrap()
-        .write_nro(
-            &mut File::create(nro.clone()).unwrap(),
+    let status = Command::new(cargo)
+        .args(["update", "-Zminimal-versions"])
+        .current_dir(project_dir)
+        .status()
+        .map_err(|source| BuildError::Command {
+            program: cargo.to_path_buf(),
+            source,
+        })?;
+    if !status.success() {
+        return Err(BuildError::MinimalVersionsFailed {
+            code: status.code().unwrap_or(1),
+        });
+    }
+
+    Ok(backup)
+}
+
+/// Fail early if the target has no prebuilt standard library (so it needs `build-std`) and the
+/// toolchain can't build it, which would otherwise fail with confusing errors (like "can't find
+/// crate for `core`") buried in the compiler output.
+///
+/// `build-std` requires a nightly toolchain (unless `RUSTC_BOOTSTRAP` is set) with the `rust-src`
+/// component.
+fn check_toolchain(project_dir: &Path, target: &str) -> Result<(), BuildError> {
+    let Some(sysroot) = rustc_sysroot(project_dir) else {
+        tracing::debug!("Failed to get the rustc sysroot, skipping the toolchain check");
+        return Ok(());
+    };
+
+    let rustlib = sysroot.join("lib").join("rustlib");
+    if rustlib.join(target).join("lib").is_dir() {
+        return Ok(());
+    }
+
+    if std::env::var_os("RUSTC_BOOTSTRAP").is_none() {
+        if let Some(release) = rustc_release(project_dir) {
+            if !release.contains("nightly") && !release.contains("-dev") {
+                return Err(BuildError::NightlyRequired {
+                    release,
+                    target: target.to_string(),
+                });
+            }
+        }
+    }
+    if !rustlib.join("src").join("rust").join("library").is_dir() {
+        return Err(BuildError::RustSrcMissing {
+            target: target.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Get the release (like `1.80.0-nightly`) of the toolchain used in the project directory.
+fn rustc_release(project_dir: &Path) -> Option<String> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc)
+        .args(["--version", "--verbose"])
+        .current_dir(project_dir)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("release:"))
+        .map(|release| release.trim().to_string())
+}
+
+/// Get the sysroot of the toolchain used in the project directory.
+fn rustc_sysroot(project_dir: &Path) -> Option<PathBuf> {
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    match Command::new(rustc)
+        .args(["--print", "sysroot"])
+        .current_dir(project_dir)
+        .output()
+    {
+        Ok(output) if output.status.success() => Some(PathBuf::from(
+            String::from_utf8_lossy(&output.stdout).trim(),
+        )),
+        _ => None,
+    }
+}
+
+/// Find `llvm-strip`: the one of the `llvm-tools` rustup component, or else the one in `PATH`.
+fn find_llvm_strip(project_dir: &Path) -> PathBuf {
+    let llvm_strip = format!("llvm-strip{}", std::env::consts::EXE_SUFFIX);
+    let rustlib = rustc_sysroot(project_dir).map(|sysroot| sysroot.join("lib").join("rustlib"));
+    rustlib
+        .and_then(|rustlib| std::fs::read_dir(rustlib).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path().join("bin").join(&llvm_strip))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(llvm_strip))
+}
+
+/// Strip the symbols of the artifact ELF into a copy next to it, returning the artifact with the
+/// stripped copy as its ELF.
+///
+/// The stripped copy has the same file stem, so the generated files are named the same.
+fn strip_artifact(artifact: &Artifact, project_dir: &Path) -> Result<Artifact, BuildError> {
+    let elf = get_artifact_elf(artifact)?;
+    let stripped_elf = get_output_elf_path_as(artifact, "stripped")?;
+
+    let llvm_strip = find_llvm_strip(project_dir);
+    let status = Command::new(&llvm_strip)
+        .arg("--strip-all")
+        .arg(elf)
+        .arg("-o")
+        .arg(&stripped_elf)
+        .status()
+        .map_err(|source| match source.kind() {
+            io::ErrorKind::NotFound => BuildError::Generation {
+                what: "stripped ELF",
+                message: String::from(
+                    "llvm-strip not found, run `rustup component add llvm-tools`",
+                ),
+            },
+            _ => BuildError::Command {
+                program: llvm_strip.clone(),
+                source,
+            },
+        })?;
+    if !status.success() {
+        return Err(BuildError::Generation {
+            what: "stripped ELF",
+            message: format!("`{}` failed ({})", llvm_strip.display(), status),
+        });
+    }
+
+    let size = |path: &Path| {
+        std::fs::metadata(path)
+            .map(|file_metadata| format_size(file_metadata.len()))
+            .unwrap_or_default()
+    };
+    log_println!(
+        "Stripped {} ({}) into {} ({}), the unstripped ELF is kept for debugging",
+        elf.to_string_lossy(),
+        size(elf),
+        stripped_elf.to_string_lossy(),
+        size(&stripped_elf)
+    );
+
+    let mut stripped_artifact = artifact.clone();
+    stripped_artifact.filenames = vec![stripped_elf];
+    Ok(stripped_artifact)
+}
+
+/// The file (inside the target directory) where the target triple of the last build is recorded.
+const TARGET_MARKER_FILE_NAME: &str = ".cargo-nx-target";
+
+/// Warn if the target triple changed since the last build, and record the current one.
+fn check_target_marker(target_dir: &Path, target: &str) {
+    let marker_path = target_dir.join(TARGET_MARKER_FILE_NAME);
+    if let Ok(last_target) = std::fs::read_to_string(&marker_path) {
+        let last_target = last_target.trim();
+        if last_target != target {
+            log_eprintln!(
+                "Warning: the target changed since the last build ({} -> {}), artifacts of the previous target are left in {} (clean it if they get in the way)",
+                last_target,
+                target,
+                target_dir.to_string_lossy()
+            );
+        }
+    }
+
+    let _ = std::fs::create_dir_all(target_dir);
+    if let Err(err) = std::fs::write(&marker_path, target) {
+        log_eprintln!(
+            "Warning: failed to write {}: {}",
+            marker_path.to_string_lossy(),
+            err
+        );
+    }
+}
+
+/// Quote a command-line argument for a POSIX shell if needed, so a printed command line can be
+/// copied and pasted.
+fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_=+.,/:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Parse a hex program ID (like `0x0100AAAABBBBCCCC`).
+pub fn parse_program_id(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let hex = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// Get the program ID of the NPDM declared in the NSP metadata, either inline or in its JSON file.
+fn npdm_program_id(root: &Path, nsp_metadata: &serde_json::Value) -> Option<u64> {
+    let npdm = match nsp_metadata.get("npdm_json").and_then(|path| path.as_str()) {
+        Some(npdm_json) => {
+            let npdm_json = std::fs::read_to_string(root.join(npdm_json)).ok()?;
+            serde_json::from_str(&npdm_json).ok()?
+        }
+        None => nsp_metadata.get("npdm")?.clone(),
+    };
+    parse_program_id(npdm.get("program_id")?.as_str()?)
+}
+
+/// Check that the NPDM program ID agrees with the NACP application ID, if the package has both.
+fn check_nacp_program_id(
+    root: &Path,
+    package_metadata: &serde_json::Value,
+) -> Result<(), BuildError> {
+    let Some(nacp_program_id) = package_metadata
+        .pointer("/nx/nro/nacp/application_id")
+        .and_then(|value| value.as_str())
+        .and_then(parse_program_id)
+    else {
+        return Ok(());
+    };
+    let Some(npdm_program_id) = package_metadata
+        .pointer("/nx/nsp")
+        .and_then(|nsp_metadata| npdm_program_id(root, nsp_metadata))
+    else {
+        return Ok(());
+    };
+
+    if npdm_program_id != nacp_program_id {
+        return Err(BuildError::ProgramIdMismatch {
+            npdm_program_id,
+            expected: "NACP application ID",
+            expected_program_id: nacp_program_id,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the program ID of the generated NPDM matches the one declared in the NSP metadata.
+///
+/// A mismatch is a warning, or an error if `strict` is set.
+fn check_npdm_program_id(
+    npdm_path: &Path,
+    declared_program_id: &str,
+    strict: bool,
+) -> Result<(), BuildError> {
+    let Some(declared_program_id) = parse_program_id(declared_program_id) else {
+        return Err(BuildError::InvalidMetadataValue {
+            field: "program_id",
+            message: format!("`{}` is not a hex program ID", declared_program_id),
+        });
+    };
+    let data = std::fs::read(npdm_path).map_err(io_error(npdm_path))?;
+    let npdm_program_id = match NpdmFile::parse(&data) {
+        Ok(npdm) => npdm.program_id,
+        Err(err) => {
+            log_eprintln!("Warning: failed to parse the generated NPDM: {}", err);
+            return Ok(());
+        }
+    };
+
+    if npdm_program_id != declared_program_id {
+        let mismatch = BuildError::ProgramIdMismatch {
+            npdm_program_id,
+            expected: "program ID declared in the package metadata",
+            expected_program_id: declared_program_id,
+        };
+        if strict {
+            return Err(mismatch);
+        }
+        log_eprintln!("Warning: {}", mismatch);
+    }
+    Ok(())
+}
+
+/// Apply the NPDM settings of the NSP metadata (like `main_thread_stack_size`) to its NPDM.
+///
+/// The settings only fill the fields the NPDM doesn't specify, unless `override_npdm` is set. An
+/// external NPDM JSON is inlined in the metadata when a setting is applied.
+fn apply_npdm_settings(
+    root: &Path,
+    nsp_metadata: &mut serde_json::Value,
+) -> Result<(), BuildError> {
+    let Some(stack_size) = nsp_metadata.get("main_thread_stack_size") else {
+        return Ok(());
+    };
+    let invalid_stack_size = |message| BuildError::InvalidMetadataValue {
+        field: "main_thread_stack_size",
+        message,
+    };
+    let stack_size: ByteSize = serde_json::from_value(stack_size.clone())
+        .map_err(|err| invalid_stack_size(err.to_string()))?;
+    if stack_size.0 == 0 || stack_size.0 % 0x1000 != 0 || stack_size.0 > u32::MAX as u64 {
+        return Err(invalid_stack_size(format!(
+            "the main thread stack size must be a non-zero multiple of 4KB (got {})",
+            format_size(stack_size.0)
+        )));
+    }
+    let override_npdm = nsp_metadata
+        .get("override_npdm")
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false);
+
+    let mut npdm = match nsp_metadata.get("npdm_json").and_then(|path| path.as_str()) {
+        Some(npdm_json) => {
+            let npdm_json_path = root.join(npdm_json);
+            let npdm_json =
+                std::fs::read_to_string(&npdm_json_path).map_err(io_error(&npdm_json_path))?;
+            serde_json::from_str(&npdm_json).map_err(|err| BuildError::Io {
+                path: npdm_json_path,
+                source: err.into(),
+            })?
+        }
+        None => match nsp_metadata.get("npdm") {
+            Some(npdm) => npdm.clone(),
+            None => return Ok(()),
+        },
+    };
+    let Some(npdm_fields) = npdm.as_object_mut() else {
+        return Ok(());
+    };
+    if override_npdm || !npdm_fields.contains_key("main_thread_stack_size") {
+        npdm_fields.insert(
+            "main_thread_stack_size".into(),
+            serde_json::Value::from(format!("{:#x}", stack_size.0)),
+        );
+    }
+
+    let nsp_metadata = nsp_metadata.as_object_mut().unwrap();
+    nsp_metadata.remove("npdm_json");
+    nsp_metadata.insert("npdm".into(), npdm);
+    Ok(())
+}
+
+/// Read the NRO metadata of a package (`/nx/nro`), with the NACP defaults filled in.
+fn read_nro_metadata(package: &Package) -> Result<NroMetadata, BuildError> {
+    let mut nro_metadata_v = package
+        .metadata
+        .pointer("/nx/nro")
+        .cloned()
+        .unwrap_or_else(|| serde_json::json!({}));
+    fill_nacp_defaults(&mut nro_metadata_v, package);
+    normalize_nacp_aliases(&mut nro_metadata_v);
+    serde_json::from_value(nro_metadata_v).map_err(|source| BuildError::InvalidMetadata {
+        package: package.name.clone(),
+        format: "nro",
+        source,
+    })
+}
+
+/// Fill the NACP name, author and version of the NRO metadata with the package ones, if absent.
+fn fill_nacp_defaults(nro_metadata: &mut serde_json::Value, package: &Package) {
+    let Some(nro_metadata) = nro_metadata.as_object_mut() else {
+        return;
+    };
+    let Some(nacp) = nro_metadata
+        .entry("nacp")
+        .or_insert_with(|| serde_json::json!({}))
+        .as_object_mut()
+    else {
+        return;
+    };
+
+    // Cargo authors usually come as `Name <email>`
+    let author = package
+        .authors
+        .first()
+        .map(|author| author.split('<').next().unwrap().trim().to_string());
+
+    let defaults = [
+        ("default_name", Some(package.name.clone())),
+        ("default_author", author),
+        ("version", Some(package.version.to_string())),
+    ];
+    for (field, value) in defaults {
+        if let Some(value) = value {
+            nacp.entry(field).or_insert(serde_json::Value::from(value));
+        }
+    }
+}
+
+/// Replace the shorthand NACP values accepted in the NRO metadata with the names linkle expects.
+fn normalize_nacp_aliases(nro_metadata: &mut serde_json::Value) {
+    let Some(startup_user_account) = nro_metadata.pointer_mut("/nacp/startup_user_account") else {
+        return;
+    };
+
+    let value = match startup_user_account.as_str() {
+        Some("none") => "None",
+        Some("required") => "Required",
+        Some("required_with_network") => "RequiredWithNetworkServiceAccountAvailable",
+        _ => return,
+    };
+    *startup_user_account = serde_json::Value::from(value);
+}
+
+/// Get the absolute path of the linker script declared in the package metadata (`/nx/ld-script`,
+/// relative to the package root), if any.
+fn linker_script(package: &Package) -> Result<Option<PathBuf>, BuildError> {
+    let Some(ld_script) = package.metadata.pointer("/nx/ld-script") else {
+        return Ok(None);
+    };
+    let Some(ld_script) = ld_script.as_str() else {
+        return Err(BuildError::InvalidMetadataValue {
+            field: "ld-script",
+            message: String::from("expected a path string"),
+        });
+    };
+
+    let ld_script = package.manifest_path.parent().unwrap().join(ld_script);
+    if !ld_script.is_file() {
+        return Err(BuildError::NotFound {
+            what: "linker script",
+            path: ld_script,
+        });
+    }
+    if ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+    {
+        log_eprintln!(
+            "Warning: RUSTFLAGS is set, so cargo ignores the flags passing the linker script {}",
+            ld_script.to_string_lossy()
+        );
+    }
+    Ok(Some(
+        ld_script.canonicalize().map_err(io_error(&ld_script))?,
+    ))
+}
+
+/// Get the linker script of the selected packages (see [`linker_script`]), if any.
+///
+/// The script applies to every binary linked by the build, so the packages with an nx format or a
+/// linker script must all declare the same one.
+fn selected_linker_script(
+    packages: &[&Package],
+    package_format: impl Fn(&Package) -> Result<Option<Format>, BuildError>,
+) -> Result<Option<PathBuf>, BuildError> {
+    let mut selected: Option<(&str, Option<PathBuf>)> = None;
+    for package in packages {
+        if package_format(package)?.is_none() && package.metadata.pointer("/nx/ld-script").is_none()
+        {
+            continue;
+        }
+
+        let ld_script = linker_script(package)?;
+        match &selected {
+            None => selected = Some((&package.name, ld_script)),
+            Some((name, selected_script)) if *selected_script != ld_script => {
+                return Err(BuildError::InvalidMetadataValue {
+                    field: "ld-script",
+                    message: format!(
+                        "packages `{}` and `{}` don't declare the same linker script, so they can't be built together, select one of them with --package",
+                        name, package.name
+                    ),
+                });
+            }
+            Some(_) => (),
+        }
+    }
+    Ok(selected.and_then(|(_, ld_script)| ld_script))
+}
+
+/// Warn if a package configured with an nx format does not depend on the `nx` runtime crate, since
+/// the build would otherwise fail later with less clear errors (like unresolved macros).
+fn warn_if_missing_nx_dependency(package: &Package, format: &str) {
+    if !package.dependencies.iter().any(|dep| dep.name == "nx") {
+        log_eprintln!(
+            "Warning: package `{}` is configured as {} but does not depend on the `nx` crate",
+            package.name,
+            format
+        );
+    }
+}
+
+/// Print the path of a generated file with its size on disk (and the one of its NSO, for an NSP),
+/// along with the time since the build started.
+fn print_built(path: &Path, nso: Option<&Path>, timings: &Timings) {
+    let size = |path: &Path| std::fs::metadata(path).map(|file_metadata| file_metadata.len());
+    let sizes = match (size(path), nso.map(size)) {
+        (Ok(size), Some(Ok(nso_size))) => {
+            format!(" ({}, NSO {})", format_size(size), format_size(nso_size))
+        }
+        (Ok(size), _) => format!(" ({})", format_size(size)),
+        (Err(_), _) => String::new(),
+    };
+    log_println!(
+        "Built {}{} in {:.1}s",
+        path.to_string_lossy(),
+        sizes,
+        timings.started.elapsed().as_secs_f64()
+    );
+}
+
+/// Get the reproducible build timestamp from the `SOURCE_DATE_EPOCH` environment variable, if set.
+///
+/// See: https://reproducible-builds.org/specs/source-date-epoch/
+fn source_date_epoch() -> Option<SystemTime> {
+    let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+    match epoch.trim().parse::<u64>() {
+        Ok(secs) => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        Err(_) => {
+            log_eprintln!(
+                "Warning: ignoring invalid SOURCE_DATE_EPOCH value `{}`",
+                epoch
+            );
+            None
+        }
+    }
+}
+
+/// Set the modification time of a generated file to `SOURCE_DATE_EPOCH`, if set.
+///
+/// This keeps the staged files (which end up in the generated containers) and the outputs
+/// themselves independent of when the project was built.
+fn apply_source_date_epoch(path: &Path) -> Result<(), BuildError> {
+    let Some(epoch) = source_date_epoch() else {
+        return Ok(());
+    };
+
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(epoch))
+        .map_err(io_error(path))
+}
+
+/// Print a summary of a generated NPDM, along with a hex dump of it if `hex_dump` is set.
+fn dump_generated_npdm(path: &Path, hex_dump: bool) -> Result<(), BuildError> {
+    let data = std::fs::read(path).map_err(io_error(path))?;
+    match NpdmFile::parse(&data) {
+        Ok(npdm) => npdm::print_summary(&npdm),
+        Err(err) => log_eprintln!("Warning: failed to parse the generated NPDM: {}", err),
+    }
+    if hex_dump {
+        npdm::print_hex_dump(&data);
+    }
+    Ok(())
+}
+
+/// Check that a generated file doesn't exceed the configured maximum output size, if any.
+fn check_output_size(path: &Path, max_output_size: Option<ByteSize>) -> Result<(), BuildError> {
+    let Some(ByteSize(max_size)) = max_output_size else {
+        return Ok(());
+    };
+
+    let size = std::fs::metadata(path).map_err(io_error(path))?.len();
+    if size > max_size {
+        return Err(BuildError::OutputTooLarge {
+            path: path.to_path_buf(),
+            size,
+            max_size,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the files and directories referenced by the nx metadata of a package exist (and
+/// that the icons are valid), for `--dry-run`.
+fn check_metadata_paths(package: &Package, format: Format) -> Result<(), BuildError> {
+    let root = package.manifest_path.parent().unwrap();
+    // A path field is either a single path or a list of them
+    let paths = |pointer: &str| -> Vec<PathBuf> {
+        match package.metadata.pointer(pointer) {
+            Some(serde_json::Value::String(path)) => vec![root.join(path)],
+            Some(serde_json::Value::Array(paths)) => paths
+                .iter()
+                .filter_map(|path| path.as_str())
+                .map(|path| root.join(path))
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+    let check_exists = |what: &'static str, path: PathBuf, is_dir: bool| {
+        if (is_dir && path.is_dir()) || (!is_dir && path.is_file()) {
+            Ok(())
+        } else {
+            Err(BuildError::NotFound { what, path })
+        }
+    };
+
+    match format {
+        Format::Nro => {
+            let mut romfs_dirs = paths("/nx/nro/romfs");
+            romfs_dirs.extend(paths("/nx/nro/romfs/dir"));
+            for dir in romfs_dirs {
+                check_exists("RomFs directory", dir, true)?;
+            }
+
+            let mut icons = paths("/nx/nro/icon");
+            if let Some(profile_icons) = package
+                .metadata
+                .pointer("/nx/nro/icons")
+                .and_then(|icons| icons.as_object())
+            {
+                icons.extend(
+                    profile_icons
+                        .values()
+                        .filter_map(|icon| icon.as_str())
+                        .map(|icon| root.join(icon)),
+                );
+            }
+            for icon in icons {
+                check_icon(&icon)?;
+            }
+        }
+        Format::Nsp => {
+            for npdm_json in paths("/nx/nsp/npdm_json") {
+                check_exists("NPDM JSON", npdm_json, false)?;
+            }
+            let mut acid_files = paths("/nx/nsp/acid/sign");
+            acid_files.extend(paths("/nx/nsp/acid/use"));
+            for acid_file in acid_files {
+                check_exists("ACID file", acid_file, false)?;
+            }
+        }
+        Format::Kip => (),
+    }
+    Ok(())
+}
+
+/// Check that an NRO icon is a JPEG with the dimensions the home menu expects.
+fn check_icon(path: &Path) -> Result<(), BuildError> {
+    if !path.is_file() {
+        return Err(BuildError::NotFound {
+            what: "icon",
+            path: path.to_path_buf(),
+        });
+    }
+    let icon = std::fs::read(path).map_err(io_error(path))?;
+    nro::check_icon(&icon).map_err(|err| BuildError::InvalidIcon {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    })
+}
+
+/// Write the default NRO icon to a temporary file, returning its path.
+///
+/// The icon is written next to the ELF, falling back to the system temporary directory if the
+/// target directory isn't writable (like a read-only `target/` in some CI sandboxes).
+fn write_default_icon(artifact: &Artifact) -> Result<PathBuf, BuildError> {
+    let temp_icon = get_output_elf_path_as(artifact, "jpg")?;
+    let target_err = match std::fs::write(&temp_icon, DEFAULT_NRO_ICON) {
+        Ok(()) => return Ok(temp_icon),
+        Err(err) => err,
+    };
+
+    let fallback_icon = std::env::temp_dir().join(format!(
+        "cargo-nx-{}",
+        temp_icon.file_name().unwrap().to_string_lossy()
+    ));
+    log_eprintln!(
+        "Warning: failed to write the default icon to {} ({}), using {} instead",
+        temp_icon.to_string_lossy(),
+        target_err,
+        fallback_icon.to_string_lossy()
+    );
+    std::fs::write(&fallback_icon, DEFAULT_NRO_ICON).map_err(io_error(&fallback_icon))?;
+    Ok(fallback_icon)
+}
+
+fn handle_nro_format(
+    root: &Path,
+    artifact: &Artifact,
+    out_dir: Option<&Path>,
+    metadata: NroMetadata,
+    profile: &str,
+    asset_cache: &AssetCache,
+    timings: &mut Timings,
+) -> Result<PathBuf, BuildError> {
+    let elf = get_artifact_elf(artifact)?.clone();
+    let is_overlay = metadata.overlay == Some(true);
+    let nro = get_output_elf_path_as(artifact, if is_overlay { "ovl" } else { "nro" })?;
+    let nro = get_output_path(
+        artifact,
+        out_dir,
+        &nro.file_name().unwrap().to_string_lossy(),
+    )?;
+
+    let romfs = timings.time("RomFs build", || -> Result<_, BuildError> {
+        let (romfs_dirs, remote_assets) = match &metadata.romfs {
+            Some(RomFsMetadata::Dirs(dirs)) => (dirs.as_slice(), &[][..]),
+            Some(RomFsMetadata::Table { dir, fetch }) => (
+                dir.as_ref().map_or(&[][..], RomFsDirs::as_slice),
+                &fetch[..],
+            ),
+            None => return Ok(None),
+        };
+
+        let staging_dir = get_output_elf_path_as(artifact, "romfs")?;
+        let mut romfs = romfs_from_directories(root, romfs_dirs, &staging_dir)?;
+        for asset in remote_assets {
+            let romfs_path = asset.romfs_path()?;
+            let asset_path = asset_cache.fetch(asset)?;
+            romfs
+                .push_file(&asset_path, &romfs_path)
+                .map_err(io_error(&asset_path))?;
+        }
+        Ok(Some(romfs))
+    })?;
+
+    // Overlays don't make use of the NRO icon
+    let icon: Option<String> = timings.time("Icon handling", || -> Result<_, BuildError> {
+        if is_overlay {
+            return Ok(None);
+        }
+
+        // Profile-specific icons take precedence over the general one
+        let profile_icon = metadata
+            .icons
+            .as_ref()
+            .and_then(|icons| icons.get(profile))
+            .map(|icon_file| root.join(icon_file))
+            .or_else(|| {
+                let icon_name = if profile == "release" {
+                    "release"
+                } else {
+                    "debug"
+                };
+                Some(root.join(format!("icon-{}.jpg", icon_name))).filter(|path| path.is_file())
+            });
+        let provided_icon = profile_icon
+            .or_else(|| metadata.icon.as_ref().map(|icon_file| root.join(icon_file)))
+            .map(|icon_path| icon_path.to_string_lossy().into_owned());
+
+        if let Some(icon) = &provided_icon {
+            check_icon(Path::new(icon))?;
+        }
+        match provided_icon {
+            Some(icon) => Ok(Some(icon)),
+            None if metadata.require_icon.unwrap_or(false) => Err(BuildError::MissingIcon),
+            _ => {
+                let temp_icon = write_default_icon(artifact)?;
+                Ok(Some(temp_icon.to_string_lossy().into_owned()))
+            }
+        }
+    })?;
+
+    let mut nxo = timings.time("ELF loading", || {
+        Nxo::from_elf(elf.to_str().unwrap()).map_err(io_error(&elf))
+    })?;
+    timings.time("NRO generation", || {
+        nxo.write_nro(
+            &mut File::create(&nro).map_err(io_error(&nro))?,
             romfs,
             icon.as_deref(),
             metadata.nacp,
         )
-        .unwrap();
+        .map_err(io_error(&nro))
+    })?;
+
+    apply_source_date_epoch(&nro)?;
+    print_built(&nro, None, timings);
+    check_output_size(&nro, metadata.max_output_size)?;
+    Ok(nro)
+}
+
+/// Write the NACP of every selected NRO package (`<package>.nacp`) to `out_dir`.
+fn handle_nacp_only(
+    packages: &[&Package],
+    package_format: impl Fn(&Package) -> Result<Option<Format>, BuildError>,
+    out_dir: &Path,
+    timings: &Timings,
+) -> Result<Vec<BuiltArtifact>, BuildError> {
+    std::fs::create_dir_all(out_dir).map_err(io_error(out_dir))?;
+
+    let mut built_artifacts = Vec::new();
+    for package in packages {
+        if package_format(package)? != Some(Format::Nro) {
+            continue;
+        }
+        let Some(mut nacp) = read_nro_metadata(package)?.nacp else {
+            return Err(BuildError::MissingMetadata("nacp"));
+        };
+
+        let nacp_path = out_dir.join(format!("{}.nacp", package.name));
+        nacp.write(&mut File::create(&nacp_path).map_err(io_error(&nacp_path))?)
+            .map_err(io_error(&nacp_path))?;
 
-    println!("Built {}", nro.to_string_lossy());
+        apply_source_date_epoch(&nacp_path)?;
+        print_built(&nacp_path, None, timings);
+        built_artifacts.push(BuiltArtifact::new(&package.name, nacp_path));
+    }
+    Ok(built_artifacts)
 }
 
-fn handle_nsp_format(root: &Path, artifact: &Artifact, metadata: NspMetadata) {
-    let elf = artifact.filenames[0].clone();
+fn handle_nsp_format(
+    root: &Path,
+    artifact: &Artifact,
+    out_dir: Option<&Path>,
+    metadata: NspMetadata,
+    args: &Args,
+    timings: &mut Timings,
+) -> Result<PathBuf, BuildError> {
+    let elf = get_artifact_elf(artifact)?.clone();
 
     let output_path = elf.parent().unwrap();
     let exefs_dir = output_path.join("exefs");
-    let _ = std::fs::remove_dir_all(exefs_dir.clone());
-    std::fs::create_dir(exefs_dir.clone()).unwrap();
+    let _ = std::fs::remove_dir_all(&exefs_dir);
+    std::fs::create_dir(&exefs_dir).map_err(io_error(&exefs_dir))?;
 
     let main_npdm = exefs_dir.join("main.npdm");
     let main_exe = exefs_dir.join("main");
 
-    let exefs_nsp = get_output_elf_path_as(artifact, "nsp");
+    let exefs_nsp = get_output_elf_path_as(artifact, "nsp")?;
+    let exefs_nsp = get_output_path(
+        artifact,
+        out_dir,
+        &exefs_nsp.file_name().unwrap().to_string_lossy(),
+    )?;
+
+    timings.time("NPDM generation", || {
+        let npdm = if let Some(npdm_json) = metadata.npdm_json {
+            let npdm_json_path = root.join(npdm_json);
+            Npdm::from_json(&npdm_json_path).map_err(|err| BuildError::Generation {
+                what: "NPDM",
+                message: format!("{}: {}", npdm_json_path.display(), err),
+            })?
+        } else if let Some(npdm) = metadata.npdm {
+            npdm
+        } else {
+            return Err(BuildError::MissingMetadata("npdm"));
+        };
+
+        let mut option = OpenOptions::new();
+        let output_option = option.write(true).create(true).truncate(true);
+        let mut out_file = output_option
+            .open(&main_npdm)
+            .map_err(io_error(&main_npdm))?;
+        let acid_path = match &metadata.acid {
+            Some(AcidMetadata::Sign(path) | AcidMetadata::Use(path)) => {
+                let acid_path = root.join(path);
+                if !acid_path.is_file() {
+                    return Err(BuildError::NotFound {
+                        what: "ACID file",
+                        path: acid_path,
+                    });
+                }
+                Some(acid_path)
+            }
+            Some(AcidMetadata::Empty) | None => None,
+        };
+        let acid_behavior = match (&metadata.acid, &acid_path) {
+            (Some(AcidMetadata::Sign(_)), Some(pem_file_path)) => {
+                AcidBehavior::Sign { pem_file_path }
+            }
+            (Some(AcidMetadata::Use(_)), Some(acid_file_path)) => {
+                AcidBehavior::Use { acid_file_path }
+            }
+            _ => AcidBehavior::Empty,
+        };
+
+        npdm.into_npdm(&mut out_file, acid_behavior)
+            .map_err(|err| BuildError::Generation {
+                what: "NPDM (is the ACID file valid?)",
+                message: err.to_string(),
+            })
+    })?;
+
+    if let Some(program_id) = &metadata.program_id {
+        check_npdm_program_id(&main_npdm, program_id, args.strict)?;
+    }
+    if args.dump_npdm {
+        dump_generated_npdm(&main_npdm, args.verbose)?;
+    }
 
-    let npdm = if let Some(npdm_json) = metadata.npdm_json {
-        let npdm_json_path = root.join(npdm_json);
-        Npdm::from_json(&npdm_json_path).unwrap()
-    } else if let Some(npdm) = metadata.npdm {
-        npdm
+    let mut nxo = timings.time("ELF loading", || {
+        Nxo::from_elf(elf.to_str().unwrap()).map_err(io_error(&elf))
+    })?;
+    // linkle compresses the segments, which are decompressed again if compression is disabled
+    let compress = metadata.compress.unwrap_or(true);
+    let (uncompressed_size, compressed_size) = timings.time("NSO generation", || {
+        let mut nso = Vec::new();
+        nxo.write_nso(&mut nso).map_err(io_error(&main_exe))?;
+        let invalid_nso = |err: io::Error| BuildError::Generation {
+            what: "NSO",
+            message: err.to_string(),
+        };
+        let compressed_size = nso.len();
+        let uncompressed_size = nso::uncompressed_size(&nso).map_err(invalid_nso)?;
+        if !compress {
+            nso = Module::parse_nso(&nso).map_err(invalid_nso)?.write_nso();
+        }
+        std::fs::write(&main_exe, nso).map_err(io_error(&main_exe))?;
+        Ok::<_, BuildError>((uncompressed_size as u64, compressed_size as u64))
+    })?;
+    if compress {
+        log_println!(
+            "Compressed the NSO segments: {} -> {}",
+            format_size(uncompressed_size),
+            format_size(compressed_size)
+        );
     } else {
-        panic!("No npdm specified")
+        log_println!(
+            "Left the NSO segments uncompressed: {} ({} compressed)",
+            format_size(uncompressed_size),
+            format_size(compressed_size)
+        );
+    }
+
+    // The PFS0 is built from the staged exefs directory
+    apply_source_date_epoch(&main_npdm)?;
+    apply_source_date_epoch(&main_exe)?;
+
+    timings.time("PFS0 generation", || {
+        let mut nsp =
+            Pfs0::from_directory(exefs_dir.to_str().unwrap()).map_err(io_error(&exefs_dir))?;
+        let mut option = OpenOptions::new();
+        let output_option = option.write(true).create(true).truncate(true);
+        nsp.write_pfs0(
+            &mut output_option
+                .open(&exefs_nsp)
+                .map_err(io_error(&exefs_nsp))?,
+        )
+        .map_err(io_error(&exefs_nsp))
+    })?;
+
+    apply_source_date_epoch(&exefs_nsp)?;
+    print_built(&exefs_nsp, Some(&main_exe), timings);
+    check_output_size(&exefs_nsp, metadata.max_output_size)?;
+    Ok(exefs_nsp)
+}
+
+fn handle_kip_format(
+    package: &Package,
+    artifact: &Artifact,
+    out_dir: Option<&Path>,
+    metadata: KipMetadata,
+    timings: &mut Timings,
+) -> Result<PathBuf, BuildError> {
+    let elf = get_artifact_elf(artifact)?.clone();
+
+    let kip_path = get_output_elf_path_as(artifact, "kip")?;
+    let kip_path = get_output_path(
+        artifact,
+        out_dir,
+        &kip_path.file_name().unwrap().to_string_lossy(),
+    )?;
+
+    let Some(program_id) = parse_program_id(&metadata.program_id) else {
+        return Err(BuildError::InvalidMetadataValue {
+            field: "program_id",
+            message: format!("`{}` is not a hex program ID", metadata.program_id),
+        });
+    };
+    // An explicit name must fit, while the package name is truncated to fit
+    let name = match &metadata.name {
+        Some(name) => name.clone(),
+        None => {
+            let mut name = package.name.clone();
+            while name.len() > kip::MAX_NAME_LEN {
+                name.pop();
+            }
+            name
+        }
     };
 
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    let mut out_file = output_option
-        .open(main_npdm.clone())
-        .map_err(|err| (err, main_npdm.clone()))
+    let mut nxo = timings.time("ELF loading", || {
+        Nxo::from_elf(elf.to_str().unwrap()).map_err(io_error(&elf))
+    })?;
+    timings.time("KIP generation", || {
+        // The KIP embeds the module segments, which are taken from the NSO generated for it
+        let mut nso = Vec::new();
+        nxo.write_nso(&mut nso).map_err(io_error(&elf))?;
+        let module = Module::parse_nso(&nso).map_err(io_error(&elf))?;
+        let kip = kip::write_kip(&module, &name, program_id, &metadata).map_err(|err| {
+            BuildError::Generation {
+                what: "KIP",
+                message: err.to_string(),
+            }
+        })?;
+        std::fs::write(&kip_path, kip).map_err(io_error(&kip_path))
+    })?;
+
+    apply_source_date_epoch(&kip_path)?;
+    print_built(&kip_path, None, timings);
+    check_output_size(&kip_path, metadata.max_output_size)?;
+    Ok(kip_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    /// Create an empty directory for a test in the system temporary directory.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-nx-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The directory of a test fixture project.
+    fn fixture_dir(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(name)
+    }
+
+    // The fixture is built for the host, whose shared libraries are ELFs on Linux
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cdylib_artifact_elf() {
+        let project_dir = fixture_dir("cdylib");
+        let metadata = read_metadata(&project_dir).unwrap();
+        assert_eq!(
+            detect_format(&metadata.packages[0]).unwrap(),
+            Some(Format::Nro)
+        );
+
+        let target_dir = test_dir("cdylib");
+        let output = Command::new(env!("CARGO"))
+            .args(["build", "--quiet", "--message-format=json"])
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .current_dir(&project_dir)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let artifact = Message::parse_stream(&output.stdout[..])
+            .filter_map(|message| match message.unwrap() {
+                Message::CompilerArtifact(artifact) => Some(artifact),
+                _ => None,
+            })
+            .find(|artifact| artifact.target.kind.contains(&"cdylib".into()))
+            .unwrap();
+        // The `rlib` is listed along with the shared library, which the NRO is generated from
+        assert!(artifact.filenames.len() > 1);
+        let elf = get_artifact_elf(&artifact).unwrap();
+        assert_eq!(elf.extension().unwrap(), "so");
+        assert!(std::fs::read(elf).unwrap().starts_with(b"\x7fELF"));
+        assert_eq!(
+            get_output_elf_path_as(&artifact, "nro").unwrap(),
+            elf.with_extension("nro")
+        );
+
+        std::fs::remove_dir_all(&target_dir).unwrap();
+    }
+
+    /// Get the target triple of the host.
+    fn host_triple() -> String {
+        let output = Command::new("rustc").arg("-vV").output().unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .unwrap()
+            .to_string()
+    }
+
+    /// Parse the `build` subcommand arguments.
+    fn parse_args(args: &[&OsStr]) -> Args {
+        let command = <Args as clap::Args>::augment_args(clap::Command::new("build"));
+        let matches = command
+            .get_matches_from(std::iter::once(OsStr::new("build")).chain(args.iter().copied()));
+        <Args as clap::FromArgMatches>::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn relocated_target_dir() {
+        let project_dir = fixture_dir("relocated-target");
+        let target_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("relocated-target-fixture");
+        let _ = std::fs::remove_dir_all(&target_dir);
+
+        // The tests run from the cargo-nx directory, which doesn't have this configuration
+        let metadata = read_metadata(&project_dir).unwrap();
+
+        let host = host_triple();
+        build(parse_args(&[
+            OsStr::new("--path"),
+            project_dir.as_os_str(),
+            OsStr::new("--target"),
+            OsStr::new(&host),
+            OsStr::new("--cargo-path"),
+            OsStr::new(env!("CARGO")),
+        ]))
         .unwrap();
-    npdm.into_npdm(&mut out_file, AcidBehavior::Empty).unwrap();
 
-    Nxo::from_elf(elf.to_str().unwrap())
-        .unwrap()
-        .write_nso(&mut File::create(main_exe).unwrap())
+        let executable = target_dir.join(&host).join("debug").join(format!(
+            "relocated-target-fixture{}",
+            std::env::consts::EXE_SUFFIX
+        ));
+        assert!(executable.is_file());
+        assert_eq!(
+            metadata.target_directory.canonicalize().unwrap(),
+            target_dir.canonicalize().unwrap()
+        );
+        assert_eq!(
+            std::fs::read_to_string(target_dir.join(TARGET_MARKER_FILE_NAME))
+                .unwrap()
+                .trim(),
+            host
+        );
+        assert!(!project_dir.join("target").exists());
+
+        std::fs::remove_dir_all(&target_dir).unwrap();
+    }
+
+    #[test]
+    fn nacp_only() {
+        let out_dir = test_dir("nacp-only");
+
+        // Nothing is compiled, so cargo is never run
+        let built = build(parse_args(&[
+            OsStr::new("--path"),
+            fixture_dir("nacp-workspace").as_os_str(),
+            OsStr::new("--nacp-only"),
+            OsStr::new("--out-dir"),
+            out_dir.as_os_str(),
+            OsStr::new("--cargo-path"),
+            OsStr::new("cargo-nx-missing-cargo"),
+        ]))
         .unwrap();
+        assert_eq!(
+            built,
+            [out_dir.join("first.nacp"), out_dir.join("second.nacp")]
+        );
+        for (path, name) in built
+            .iter()
+            .zip(["The first package", "The second package"])
+        {
+            let nacp = std::fs::read(path).unwrap();
+            assert_eq!(nacp.len(), 0x4000);
+            assert!(nacp.starts_with(name.as_bytes()));
+        }
+
+        // The NACP made up of the package defaults alone isn't written
+        assert!(matches!(
+            build(parse_args(&[
+                OsStr::new("--path"),
+                fixture_dir("cdylib").as_os_str(),
+                OsStr::new("--nacp-only"),
+                OsStr::new("--out-dir"),
+                out_dir.as_os_str(),
+            ])),
+            Err(BuildError::MissingMetadata("nacp"))
+        ));
+
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
 
-    let mut nsp = Pfs0::from_directory(exefs_dir.to_str().unwrap()).unwrap();
-    let mut option = OpenOptions::new();
-    let output_option = option.write(true).create(true).truncate(true);
-    nsp.write_pfs0(
-        &mut output_option
-            .open(exefs_nsp.clone())
-            .map_err(|err| (err, exefs_nsp.clone()))
-            .unwrap(),
-    )
-    .map_err(|err| (err, exefs_nsp.clone()))
-    .unwrap();
-
-    println!("Built {}", exefs_nsp.to_string_lossy());
+    #[test]
+    fn linker_script_selection() {
+        let project_dir = fixture_dir("ld-scripts");
+        let metadata = read_metadata(&project_dir).unwrap();
+        let packages: Vec<&Package> = metadata.packages.iter().collect();
+
+        assert_eq!(
+            selected_linker_script(&packages[..1], detect_format).unwrap(),
+            Some(
+                project_dir
+                    .join(&packages[0].name)
+                    .join("link.ld")
+                    .canonicalize()
+                    .unwrap()
+            )
+        );
+        assert!(matches!(
+            selected_linker_script(&packages, detect_format),
+            Err(BuildError::InvalidMetadataValue {
+                field: "ld-script",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn lockfile_backup_restores_on_drop() {
+        let dir = test_dir("lockfile-backup");
+        let lockfile = dir.join("Cargo.lock");
+
+        std::fs::write(&lockfile, "original").unwrap();
+        let backup = LockfileBackup {
+            path: lockfile.clone(),
+            contents: std::fs::read(&lockfile).ok(),
+        };
+        std::fs::write(&lockfile, "minimal").unwrap();
+        drop(backup);
+        assert_eq!(std::fs::read_to_string(&lockfile).unwrap(), "original");
+
+        // A lockfile which didn't exist before is removed
+        std::fs::remove_file(&lockfile).unwrap();
+        let backup = LockfileBackup {
+            path: lockfile.clone(),
+            contents: None,
+        };
+        std::fs::write(&lockfile, "minimal").unwrap();
+        drop(backup);
+        assert!(!lockfile.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,33 @@
+//! Helpers to read and write little-endian binary formats.
+
+use std::io;
+
+/// Get `len` bytes of `data` at `offset`, failing if they are out of bounds.
+pub fn slice(data: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid_data("unexpected end of data"))
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = slice(data, offset, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    let bytes = slice(data, offset, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
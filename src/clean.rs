@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    build::{self, read_metadata},
+    config::{self, Config},
+};
+
+/// The extensions of the files generated next to each built ELF.
+const GENERATED_EXTENSIONS: &[&str] = &["nro", "ovl", "nsp", "kip", "jpg", "romfs", "stripped"];
+
+/// The files and directories generated once per output directory.
+const GENERATED_SHARED_ITEMS: &[&str] = &["control.nacp", "exefs"];
+
+/// The `clean` subcommand CLI arguments.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Removes the artifacts of the release profile.
+    #[arg(short, long, overrides_with = "no_release")]
+    pub release: bool,
+    /// Removes the artifacts of the dev profile, even if the configuration file sets `release`.
+    #[arg(long, overrides_with = "release")]
+    pub no_release: bool,
+    /// The path to the project to clean.
+    #[arg(short, long, default_value = ".", value_name = "DIR", value_parser)]
+    pub path: PathBuf,
+    /// The custom target triple the artifacts were built for, if any.
+    #[arg(short, long)]
+    pub target: Option<String>,
+}
+
+/// Handle the `clean` subcommand.
+///
+/// Only the files generated by `cargo nx build` are removed, keeping the compiled ELFs (and the
+/// rest of the target directory) intact.
+pub fn handle_subcommand(args: Args) {
+    let metadata = read_metadata(&args.path).unwrap();
+
+    let config = Config::load(&args.path).build;
+    let release = config::resolve_flag(args.release, args.no_release, config.release);
+    // The target is resolved like `build` does, from its main package
+    let main_package = match build::default_packages(&metadata.packages, build::detect_format) {
+        Ok(packages) => packages[0],
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let (_, target) = build::resolve_target(
+        args.target.as_deref(),
+        main_package,
+        config.target.as_deref(),
+        &args.path,
+    );
+
+    let output_dir =
+        metadata
+            .target_directory
+            .join(&target)
+            .join(if release { "release" } else { "debug" });
+
+    let mut removed = 0;
+    for package in &metadata.packages {
+        for target in &package.targets {
+            let stem = if target.kind.contains(&"bin".into()) {
+                target.name.clone()
+            } else if target.kind.contains(&"cdylib".into()) {
+                format!("lib{}", target.name.replace('-', "_"))
+            } else {
+                continue;
+            };
+
+            for extension in GENERATED_EXTENSIONS {
+                let path = output_dir.join(&stem).with_extension(extension);
+                removed += remove_generated(&path) as usize;
+            }
+        }
+    }
+    for item in GENERATED_SHARED_ITEMS {
+        removed += remove_generated(&output_dir.join(item)) as usize;
+    }
+
+    println!("Removed {} generated files", removed);
+}
+
+/// Remove a generated file or directory if it exists, returning whether it was removed.
+fn remove_generated(path: &Path) -> bool {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else if path.is_file() {
+        std::fs::remove_file(path)
+    } else {
+        return false;
+    };
+
+    match result {
+        Ok(()) => {
+            println!("Removed {}", path.to_string_lossy());
+            true
+        }
+        Err(err) => {
+            eprintln!(
+                "Warning: failed to remove {}: {}",
+                path.to_string_lossy(),
+                err
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! The `cargo-nx.toml` configuration file.
+//!
+//! It sets default values for the most common command-line arguments, so they don't need to be
+//! passed on every invocation. Two configuration files are read, if present:
+//!
+//! - The user-global one, `cargo-nx.toml` inside the user configuration directory.
+//! - The project-local one, `cargo-nx.toml` next to the project's `Cargo.toml`.
+//!
+//! Values from the project-local file override the user-global ones, and command-line arguments
+//! override both.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::run::Emulator;
+
+/// The configuration file name.
+const CONFIG_FILE_NAME: &str = "cargo-nx.toml";
+
+/// The `cargo-nx` configuration.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub build: BuildConfig,
+    pub link: LinkConfig,
+    pub run: RunConfig,
+}
+
+/// The `[build]` configuration defaults.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildConfig {
+    /// Build using the release profile.
+    pub release: Option<bool>,
+    /// The target triple to use.
+    pub target: Option<String>,
+}
+
+/// The `[link]` configuration defaults.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConfig {
+    /// The IP address of the netloader server.
+    pub address: Option<IpAddr>,
+    /// The number of times to retry server discovery.
+    pub retries: Option<u32>,
+    /// The port the netloader server listens on.
+    pub server_port: Option<u16>,
+    /// The port the discovery response and the stdio connection are received on.
+    pub client_port: Option<u16>,
+    /// Start the nxlink stdio server after sending the file.
+    pub server: Option<bool>,
+    /// Send the file data without compressing it.
+    pub compress_none: Option<bool>,
+    /// The compression level of the file data (from 0, no compression, to 9).
+    pub compression_level: Option<u32>,
+}
+
+/// The `[run]` configuration defaults.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    /// The emulator to run the built project with.
+    pub emulator: Option<Emulator>,
+    /// The path to the emulator executable.
+    pub emulator_path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Load the user-global configuration, overridden by the one in `project_dir`.
+    ///
+    /// Exits the process if a configuration file exists but can't be parsed.
+    pub fn load(project_dir: &Path) -> Self {
+        let global = user_config_dir().and_then(|dir| read_config(&dir.join(CONFIG_FILE_NAME)));
+        let local = read_config(&project_dir.join(CONFIG_FILE_NAME));
+
+        match (local, global) {
+            (Some(local), Some(global)) => local.merge(global),
+            (local, global) => local.or(global).unwrap_or_default(),
+        }
+    }
+
+    /// Fill the unset values of `self` with the ones of `other`.
+    fn merge(self, other: Self) -> Self {
+        Self {
+            build: BuildConfig {
+                release: self.build.release.or(other.build.release),
+                target: self.build.target.or(other.build.target),
+            },
+            link: LinkConfig {
+                address: self.link.address.or(other.link.address),
+                retries: self.link.retries.or(other.link.retries),
+                server_port: self.link.server_port.or(other.link.server_port),
+                client_port: self.link.client_port.or(other.link.client_port),
+                server: self.link.server.or(other.link.server),
+                compress_none: self.link.compress_none.or(other.link.compress_none),
+                compression_level: self.link.compression_level.or(other.link.compression_level),
+            },
+            run: RunConfig {
+                emulator: self.run.emulator.or(other.run.emulator),
+                emulator_path: self.run.emulator_path.or(other.run.emulator_path),
+            },
+        }
+    }
+}
+
+/// Resolve a flag which can be enabled (`--<flag>`) or disabled (`--no-<flag>`) in the command line,
+/// falling back to its configured value and else to disabled.
+pub fn resolve_flag(enabled: bool, disabled: bool, configured: Option<bool>) -> bool {
+    if enabled || disabled {
+        enabled
+    } else {
+        configured.unwrap_or(false)
+    }
+}
+
+/// Read and parse a configuration file, if it exists.
+fn read_config(path: &Path) -> Option<Config> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    match toml::from_str(&contents) {
+        Ok(config) => Some(config),
+        Err(err) => {
+            eprintln!("Failed to parse {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Get the user configuration directory.
+fn user_config_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        return std::env::var_os("APPDATA").map(PathBuf::from);
+    }
+
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_configuration() {
+        assert!(resolve_flag(true, false, Some(false)));
+        assert!(!resolve_flag(false, true, Some(true)));
+        assert!(resolve_flag(false, false, Some(true)));
+        assert!(!resolve_flag(false, false, None));
+    }
+
+    #[test]
+    fn local_configuration_overrides_global() {
+        let local: Config = toml::from_str("[link]\nserver = false\n").unwrap();
+        let global: Config = toml::from_str(
+            "[build]\nrelease = true\n[link]\nserver = true\ncompression_level = 9\n",
+        )
+        .unwrap();
+        let config = local.merge(global);
+        assert_eq!(config.build.release, Some(true));
+        assert_eq!(config.link.server, Some(false));
+        assert_eq!(config.link.compression_level, Some(9));
+    }
+}
@@ -0,0 +1,193 @@
+//! The `cargo nx convert` subcommand.
+//!
+//! It re-wraps the module of an existing NRO into an NSP (exefs PFS0), or the `main` NSO of an
+//! NSP into an NRO, without rebuilding the project.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+use linkle::format::{
+    nacp::Nacp,
+    npdm::{AcidBehavior, Npdm},
+    pfs0::Pfs0,
+};
+
+use crate::{
+    build::{Format, DEFAULT_NRO_ICON},
+    bytes::invalid_data,
+    nro::{self, NroFile},
+    nso::Module,
+    pfs0::{self, PFS0_MAGIC},
+    size::format_size,
+};
+
+/// The `convert` subcommand CLI arguments.
+#[derive(clap::Args)]
+pub struct Args {
+    /// The format to convert to.
+    #[arg(long, value_enum)]
+    pub to: Format,
+    /// The converted file path [default: the input path with the new extension].
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+    /// The NPDM JSON of the NSP (required to convert to an NSP).
+    #[arg(long, value_name = "JSON", required_if_eq("to", "nsp"))]
+    pub npdm: Option<PathBuf>,
+    /// Sign the ACID of the NPDM with this PEM private key, instead of leaving it empty.
+    #[arg(long, value_name = "PEM")]
+    pub acid_key: Option<PathBuf>,
+    /// The icon (JPEG) of the NRO [default: the default NRO icon].
+    #[arg(long, value_name = "JPG")]
+    pub icon: Option<PathBuf>,
+    /// The NACP fields of the NRO, as a JSON file [default: the file name as the title name].
+    #[arg(long, value_name = "JSON")]
+    pub nacp: Option<PathBuf>,
+    /// The NRO or NSP file to convert.
+    #[arg(value_name = "FILE")]
+    pub input: PathBuf,
+}
+
+/// Handle the `convert` subcommand.
+pub fn handle_subcommand(args: Args) {
+    let data = match std::fs::read(&args.input) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", args.input.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let output = args.output.clone().unwrap_or_else(|| {
+        args.input.with_extension(match args.to {
+            Format::Nro => "nro",
+            Format::Nsp => "nsp",
+            Format::Kip => "kip",
+        })
+    });
+    if output == args.input {
+        eprintln!("The output path is the same as the input one, specify another with --output");
+        std::process::exit(1);
+    }
+
+    let res = match (args.to, data.starts_with(PFS0_MAGIC)) {
+        (Format::Nsp, false) => nro_to_nsp(&args, data, &output),
+        (Format::Nro, true) => nsp_to_nro(&args, &data, &output),
+        (Format::Nsp, true) => Err(invalid_data("the input file is already an NSP")),
+        (Format::Nro, false) => Err(invalid_data("the input file is not an NSP")),
+        (Format::Kip, _) => Err(invalid_data("converting to a KIP is not supported")),
+    };
+    match res {
+        Ok(()) => match std::fs::metadata(&output) {
+            Ok(file_metadata) => println!(
+                "Converted {} to {} ({})",
+                args.input.display(),
+                output.display(),
+                format_size(file_metadata.len())
+            ),
+            Err(_) => println!("Converted {} to {}", args.input.display(), output.display()),
+        },
+        Err(err) => {
+            eprintln!("Failed to convert {}: {}", args.input.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Convert an NRO into an NSP, with the NPDM generated from the specified JSON.
+fn nro_to_nsp(args: &Args, data: Vec<u8>, output: &Path) -> io::Result<()> {
+    let nro = NroFile::parse(data)?;
+    let module = nro.module()?;
+    if nro.romfs()?.is_some() {
+        eprintln!("Warning: the RomFs of the NRO can't be embedded in the NSP and is left out");
+    }
+
+    // The PFS0 is built from an exefs directory staged in a fresh temporary directory
+    let staging_dir = StagingDir::new()?;
+    let exefs_dir = staging_dir.path();
+    std::fs::write(exefs_dir.join("main"), module.write_nso())?;
+
+    let npdm_json = args.npdm.as_deref().unwrap();
+    let npdm = Npdm::from_json(npdm_json)
+        .map_err(|err| invalid_data(&format!("invalid NPDM JSON: {:?}", err)))?;
+    let acid_behavior = match &args.acid_key {
+        Some(pem_file_path) => AcidBehavior::Sign { pem_file_path },
+        None => AcidBehavior::Empty,
+    };
+    npdm.into_npdm(
+        &mut File::create(exefs_dir.join("main.npdm"))?,
+        acid_behavior,
+    )
+    .map_err(|err| invalid_data(&format!("failed to generate the NPDM: {:?}", err)))?;
+
+    let mut nsp = Pfs0::from_directory(exefs_dir.to_str().unwrap())?;
+    nsp.write_pfs0(&mut File::create(output)?)
+}
+
+/// A uniquely named directory created in the system temporary directory, removed when dropped.
+struct StagingDir(PathBuf);
+
+impl StagingDir {
+    fn new() -> io::Result<Self> {
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "cargo-nx-exefs-{}-{:016x}",
+                std::process::id(),
+                rand::random::<u64>()
+            ));
+            // Creating the directory fails if it already exists, so it can't be someone else's
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.0) {
+            eprintln!(
+                "Warning: failed to remove the staging directory {}: {}",
+                self.0.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Convert an NSP into an NRO, with the specified (or default) icon and NACP.
+fn nsp_to_nro(args: &Args, data: &[u8], output: &Path) -> io::Result<()> {
+    let module = Module::parse_nso(pfs0::read_file(data, "main")?)?;
+
+    let icon = match &args.icon {
+        Some(icon_path) => {
+            let icon = std::fs::read(icon_path)?;
+            nro::check_icon(&icon).map_err(|err| {
+                invalid_data(&format!("invalid icon {}: {}", icon_path.display(), err))
+            })?;
+            icon
+        }
+        None => DEFAULT_NRO_ICON.to_vec(),
+    };
+
+    let nacp_fields = match &args.nacp {
+        Some(nacp_path) => serde_json::from_slice(&std::fs::read(nacp_path)?)?,
+        None => {
+            let name = args.input.file_stem().unwrap().to_string_lossy();
+            serde_json::json!({ "default_name": name })
+        }
+    };
+    let mut nacp: Nacp = serde_json::from_value(nacp_fields)?;
+    let mut nacp_data = Vec::new();
+    nacp.write(&mut nacp_data)?;
+
+    std::fs::write(output, nro::write_nro(&module, &icon, &nacp_data)?)
+}
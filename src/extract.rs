@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use crate::nro::{extract_romfs, NroFile};
+
+/// The `extract` subcommand CLI arguments.
+#[derive(clap::Args)]
+pub struct Args {
+    /// Extract the embedded RomFs files into this directory.
+    #[arg(long, value_name = "DIR")]
+    pub romfs: Option<PathBuf>,
+    /// Save the embedded icon to this file.
+    #[arg(long, value_name = "PATH")]
+    pub icon: Option<PathBuf>,
+    /// Save the embedded NACP to this file.
+    #[arg(long, value_name = "PATH")]
+    pub nacp: Option<PathBuf>,
+    /// The NRO file to extract the assets from.
+    #[arg(value_name = "FILE")]
+    pub nro_file: PathBuf,
+}
+
+/// Handle the `extract` subcommand.
+pub fn handle_subcommand(args: Args) {
+    if args.romfs.is_none() && args.icon.is_none() && args.nacp.is_none() {
+        eprintln!("Nothing to extract, specify at least one of --romfs, --icon or --nacp");
+        std::process::exit(1);
+    }
+
+    let nro = match NroFile::read(&args.nro_file) {
+        Ok(nro) => nro,
+        Err(err) => {
+            eprintln!("Failed to parse {}: {}", args.nro_file.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(romfs_dir) = &args.romfs {
+        let romfs = unwrap_asset(nro.romfs(), "RomFs");
+        match extract_romfs(romfs, romfs_dir) {
+            Ok(count) => println!("Extracted {} RomFs files to {}", count, romfs_dir.display()),
+            Err(err) => {
+                eprintln!("Failed to extract the RomFs: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(icon_path) = &args.icon {
+        save_asset(unwrap_asset(nro.icon(), "icon"), icon_path, "icon");
+    }
+
+    if let Some(nacp_path) = &args.nacp {
+        save_asset(unwrap_asset(nro.nacp(), "NACP"), nacp_path, "NACP");
+    }
+}
+
+/// Get an asset of the NRO, exiting if it's missing or invalid.
+fn unwrap_asset<'a>(asset: std::io::Result<Option<&'a [u8]>>, name: &str) -> &'a [u8] {
+    match asset {
+        Ok(Some(asset)) => asset,
+        Ok(None) => {
+            eprintln!("The NRO has no embedded {}", name);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to read the NRO {}: {}", name, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn save_asset(asset: &[u8], path: &Path, name: &str) {
+    if let Err(err) = std::fs::write(path, asset) {
+        eprintln!("Failed to save the {} to {}: {}", name, path.display(), err);
+        std::process::exit(1);
+    }
+    println!("Saved {} to {}", name, path.display());
+}
@@ -0,0 +1,232 @@
+//! The `cargo nx info` subcommand.
+//!
+//! It prints a report of an existing NRO, NSP, NSO or KIP: the module segments, and the assets
+//! (icon, NACP and RomFs) of an NRO or the NPDM of an NSP.
+
+use std::{io, path::PathBuf};
+
+use crate::{
+    bytes::{read_u32, read_u64, slice},
+    npdm::{self, NpdmFile},
+    nro::{self, NroFile},
+    nso::Module,
+    pfs0::{self, PFS0_MAGIC},
+    size::format_size,
+};
+
+/// The NACP title languages, in the order of their entries.
+const NACP_LANGUAGES: &[&str] = &[
+    "AmericanEnglish",
+    "BritishEnglish",
+    "Japanese",
+    "French",
+    "German",
+    "LatinAmericanSpanish",
+    "Spanish",
+    "Italian",
+    "Dutch",
+    "CanadianFrench",
+    "Portuguese",
+    "Russian",
+    "Korean",
+    "TraditionalChinese",
+    "SimplifiedChinese",
+    "BrazilianPortuguese",
+];
+
+/// The `info` subcommand CLI arguments.
+#[derive(clap::Args)]
+pub struct Args {
+    /// The NRO, NSP, NSO or KIP file to inspect.
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+}
+
+/// Handle the `info` subcommand.
+pub fn handle_subcommand(args: Args) {
+    let data = match std::fs::read(&args.file) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to read {}: {}", args.file.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "File: {} ({})",
+        args.file.display(),
+        format_size(data.len() as u64)
+    );
+    let res = if data.starts_with(PFS0_MAGIC) {
+        print_nsp_info(&data)
+    } else if data.starts_with(b"NSO0") {
+        println!("Format: NSO");
+        Module::parse_nso(&data).map(|module| print_module_info(&module))
+    } else if data.starts_with(b"KIP1") {
+        print_kip_info(&data)
+    } else {
+        print_nro_info(data)
+    };
+    if let Err(err) = res {
+        eprintln!("Failed to parse {}: {}", args.file.display(), err);
+        std::process::exit(1);
+    }
+}
+
+/// Print the segments and module ID of a module.
+fn print_module_info(module: &Module) {
+    let [text, rodata, data] = &module.segments;
+    println!(
+        "Segments: .text {}, .rodata {}, .data {}, .bss {}",
+        format_size(text.data.len() as u64),
+        format_size(rodata.data.len() as u64),
+        format_size(data.data.len() as u64),
+        format_size(module.bss_size as u64)
+    );
+
+    // The module ID is usually a shorter build ID padded with zeros
+    let module_id_len = module
+        .module_id
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |index| index + 1);
+    let module_id: Vec<_> = module.module_id[..module_id_len]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    let module_id = module_id.concat();
+    println!(
+        "Module ID: {}",
+        if module_id.is_empty() {
+            "none"
+        } else {
+            &module_id
+        }
+    );
+}
+
+/// Print the module, icon, NACP and RomFs of an NRO.
+fn print_nro_info(data: Vec<u8>) -> io::Result<()> {
+    let nro = NroFile::parse(data)?;
+    println!("Format: NRO");
+    print_module_info(&nro.module()?);
+
+    match nro.icon()? {
+        Some(icon) => match nro::jpeg_dimensions(icon) {
+            Ok((width, height)) => println!(
+                "Icon: {}x{} JPEG ({})",
+                width,
+                height,
+                format_size(icon.len() as u64)
+            ),
+            Err(err) => println!("Icon: invalid ({})", err),
+        },
+        None => println!("Icon: none"),
+    }
+
+    match nro.nacp()? {
+        Some(nacp) => print_nacp_info(nacp)?,
+        None => println!("NACP: none"),
+    }
+
+    match nro.romfs()? {
+        Some(romfs) => println!("RomFs: {}", format_size(romfs.len() as u64)),
+        None => println!("RomFs: none"),
+    }
+    Ok(())
+}
+
+/// Print the titles and version of a NACP.
+fn print_nacp_info(nacp: &[u8]) -> io::Result<()> {
+    let read_str = |offset: usize, len: usize| -> io::Result<String> {
+        let field = slice(nacp, offset, len)?;
+        let end = field.iter().position(|&c| c == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&field[..end]).into_owned())
+    };
+
+    // Each title entry holds a name (0x200 bytes) and an author (0x100 bytes)
+    let mut titles = Vec::new();
+    for (index, language) in NACP_LANGUAGES.iter().enumerate() {
+        let name = read_str(index * 0x300, 0x200)?;
+        let author = read_str(index * 0x300 + 0x200, 0x100)?;
+        if !name.is_empty() || !author.is_empty() {
+            titles.push((*language, name, author));
+        }
+    }
+
+    match titles.first() {
+        Some((_, name, author)) => {
+            println!("Title: {}", name);
+            println!("Author: {}", author);
+        }
+        None => println!("Title: none"),
+    }
+    println!("Version: {}", read_str(0x3060, 0x10)?);
+
+    // The per-language titles are only listed if they aren't all the same
+    if titles
+        .iter()
+        .any(|(_, name, author)| (name, author) != (&titles[0].1, &titles[0].2))
+    {
+        println!("Titles:");
+        for (language, name, author) in &titles {
+            println!("  {}: {} ({})", language, name, author);
+        }
+    }
+    Ok(())
+}
+
+/// Print the files of an NSP, along with its `main` module and NPDM.
+fn print_nsp_info(data: &[u8]) -> io::Result<()> {
+    println!("Format: NSP");
+    let files = pfs0::read_files(data)?;
+    println!("Files:");
+    for (name, contents) in &files {
+        println!("  {} ({})", name, format_size(contents.len() as u64));
+    }
+
+    for (name, contents) in &files {
+        match name.as_str() {
+            "main" => print_module_info(&Module::parse_nso(contents)?),
+            "main.npdm" => {
+                println!("NPDM:");
+                npdm::print_summary(&NpdmFile::parse(contents)?);
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Print the header, segments and kernel capabilities of a KIP.
+fn print_kip_info(data: &[u8]) -> io::Result<()> {
+    println!("Format: KIP");
+    let name = slice(data, 0x4, 0xC)?;
+    let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    println!("Name: {}", String::from_utf8_lossy(&name[..name_len]));
+    println!("Program ID: {:#018X}", read_u64(data, 0x10)?);
+    println!("Version: {}", read_u32(data, 0x18)?);
+
+    let flags = slice(data, 0x1F, 1)?[0];
+    println!(
+        "Main thread: priority {}, core {}, stack size {:#X}",
+        slice(data, 0x1C, 1)?[0],
+        slice(data, 0x1D, 1)?[0],
+        read_u32(data, 0x3C)?
+    );
+    println!("64-bit: {}", flags & (1 << 3) != 0);
+    println!("Compressed: {}", flags & 0b111 != 0);
+    println!(
+        "Segments: .text {}, .rodata {}, .data {}, .bss {}",
+        format_size(read_u32(data, 0x24)? as u64),
+        format_size(read_u32(data, 0x34)? as u64),
+        format_size(read_u32(data, 0x44)? as u64),
+        format_size(read_u32(data, 0x54)? as u64)
+    );
+
+    println!("Kernel capabilities:");
+    for capability in npdm::parse_kernel_capabilities(slice(data, 0x80, 0x80)?)? {
+        println!("  {}", capability);
+    }
+    Ok(())
+}
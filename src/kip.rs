@@ -0,0 +1,563 @@
+//! Writing of KIP1 files, the format of the sysmodules loaded at boot (like by Atmosphère).
+//!
+//! A KIP embeds the (uncompressed) segments of a module along with the process settings and kernel
+//! capabilities that an NSP keeps in its NPDM.
+//!
+//! See: https://switchbrew.org/wiki/KIP
+
+use std::io;
+
+use crate::{
+    bytes::{invalid_data, write_u32, write_u64},
+    nso::Module,
+    size::ByteSize,
+};
+
+/// The KIP header magic.
+const KIP_MAGIC: &[u8] = b"KIP1";
+
+/// The KIP header size (including the kernel capabilities), after which the segments are stored.
+const KIP_HEADER_SIZE: usize = 0x100;
+
+/// The maximum size of the KIP name.
+pub const MAX_NAME_LEN: usize = 0xC;
+
+/// The offset of the kernel capability descriptors in the header.
+const KERNEL_CAPABILITIES_OFFSET: usize = 0x80;
+
+/// The maximum number of kernel capability descriptors.
+const MAX_KERNEL_CAPABILITIES: usize = 0x20;
+
+/// The kernel capability descriptor used as padding.
+const KERNEL_CAPABILITY_EMPTY: u32 = 0xFFFF_FFFF;
+
+/// The number of system calls each `EnableSystemCalls` descriptor covers.
+const SYSCALLS_PER_DESCRIPTOR: u32 = 24;
+
+/// The system call names, indexed by their ID (unassigned IDs are empty).
+const SYSTEM_CALLS: &[&str] = &[
+    "",
+    "SetHeapSize",
+    "SetMemoryPermission",
+    "SetMemoryAttribute",
+    "MapMemory",
+    "UnmapMemory",
+    "QueryMemory",
+    "ExitProcess",
+    "CreateThread",
+    "StartThread",
+    "ExitThread",
+    "SleepThread",
+    "GetThreadPriority",
+    "SetThreadPriority",
+    "GetThreadCoreMask",
+    "SetThreadCoreMask",
+    "GetCurrentProcessorNumber",
+    "SignalEvent",
+    "ClearEvent",
+    "MapSharedMemory",
+    "UnmapSharedMemory",
+    "CreateTransferMemory",
+    "CloseHandle",
+    "ResetSignal",
+    "WaitSynchronization",
+    "CancelSynchronization",
+    "ArbitrateLock",
+    "ArbitrateUnlock",
+    "WaitProcessWideKeyAtomic",
+    "SignalProcessWideKey",
+    "GetSystemTick",
+    "ConnectToNamedPort",
+    "SendSyncRequestLight",
+    "SendSyncRequest",
+    "SendSyncRequestWithUserBuffer",
+    "SendAsyncRequestWithUserBuffer",
+    "GetProcessId",
+    "GetThreadId",
+    "Break",
+    "OutputDebugString",
+    "ReturnFromException",
+    "GetInfo",
+    "FlushEntireDataCache",
+    "FlushDataCache",
+    "MapPhysicalMemory",
+    "UnmapPhysicalMemory",
+    "GetDebugFutureThreadInfo",
+    "GetLastThreadInfo",
+    "GetResourceLimitLimitValue",
+    "GetResourceLimitCurrentValue",
+    "SetThreadActivity",
+    "GetThreadContext3",
+    "WaitForAddress",
+    "SignalToAddress",
+    "SynchronizePreemptionState",
+    "GetResourceLimitPeakValue",
+    "",
+    "CreateIoPool",
+    "CreateIoRegion",
+    "",
+    "KernelDebug",
+    "ChangeKernelTraceState",
+    "",
+    "",
+    "CreateSession",
+    "AcceptSession",
+    "ReplyAndReceiveLight",
+    "ReplyAndReceive",
+    "ReplyAndReceiveWithUserBuffer",
+    "CreateEvent",
+    "MapIoRegion",
+    "UnmapIoRegion",
+    "MapPhysicalMemoryUnsafe",
+    "UnmapPhysicalMemoryUnsafe",
+    "SetUnsafeLimit",
+    "CreateCodeMemory",
+    "ControlCodeMemory",
+    "SleepSystem",
+    "ReadWriteRegister",
+    "SetProcessActivity",
+    "CreateSharedMemory",
+    "MapTransferMemory",
+    "UnmapTransferMemory",
+    "CreateInterruptEvent",
+    "QueryPhysicalAddress",
+    "QueryIoMapping",
+    "CreateDeviceAddressSpace",
+    "AttachDeviceAddressSpace",
+    "DetachDeviceAddressSpace",
+    "MapDeviceAddressSpaceByForce",
+    "MapDeviceAddressSpaceAligned",
+    "MapDeviceAddressSpace",
+    "UnmapDeviceAddressSpace",
+    "InvalidateProcessDataCache",
+    "StoreProcessDataCache",
+    "FlushProcessDataCache",
+    "DebugActiveProcess",
+    "BreakDebugProcess",
+    "TerminateDebugProcess",
+    "GetDebugEvent",
+    "ContinueDebugEvent",
+    "GetProcessList",
+    "GetThreadList",
+    "GetDebugThreadContext",
+    "SetDebugThreadContext",
+    "QueryDebugProcessMemory",
+    "ReadDebugProcessMemory",
+    "WriteDebugProcessMemory",
+    "SetHardwareBreakPoint",
+    "GetDebugThreadParam",
+    "",
+    "GetSystemInfo",
+    "CreatePort",
+    "ManageNamedPort",
+    "ConnectToPort",
+    "SetProcessMemoryPermission",
+    "MapProcessMemory",
+    "UnmapProcessMemory",
+    "QueryProcessMemory",
+    "MapProcessCodeMemory",
+    "UnmapProcessCodeMemory",
+    "CreateProcess",
+    "StartProcess",
+    "TerminateProcess",
+    "GetProcessInfo",
+    "CreateResourceLimit",
+    "SetResourceLimitLimitValue",
+    "CallSecureMonitor",
+];
+
+/// The KIP metadata of a package (`package.metadata.nx.kip`).
+#[derive(Debug, serde::Deserialize)]
+pub struct KipMetadata {
+    /// The process name (at most 12 bytes) [default: the package name].
+    pub name: Option<String>,
+    /// The program ID, as a hex string.
+    pub program_id: String,
+    /// The process version.
+    #[serde(default)]
+    pub version: u32,
+    /// The main thread priority.
+    pub main_thread_priority: u8,
+    /// The main thread CPU core.
+    pub main_thread_core_number: u8,
+    /// The main thread stack size (a multiple of 4KB).
+    pub main_thread_stack_size: ByteSize,
+    /// The process flags.
+    #[serde(default)]
+    pub flags: KipFlags,
+    /// The kernel capabilities of the process.
+    pub kernel_capabilities: KernelCapabilities,
+    /// The maximum size of the generated KIP.
+    pub max_output_size: Option<ByteSize>,
+}
+
+/// The flags of a KIP process.
+#[derive(Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct KipFlags {
+    /// Whether the process uses 64-bit instructions.
+    pub is_64_bit: bool,
+    /// Whether the process uses a 64-bit (39-bit) address space.
+    pub address_space_64_bit: bool,
+    /// Whether the process memory is allocated from the secure memory pool.
+    pub use_secure_memory: bool,
+    /// Whether the process can't be terminated.
+    pub immortal: bool,
+}
+
+impl Default for KipFlags {
+    fn default() -> Self {
+        Self {
+            is_64_bit: true,
+            address_space_64_bit: true,
+            use_secure_memory: false,
+            immortal: false,
+        }
+    }
+}
+
+/// The kernel capabilities of a KIP process, like the `kernel_capabilities` of an NPDM.
+#[derive(Debug, serde::Deserialize)]
+pub struct KernelCapabilities {
+    /// The numerically highest thread priority allowed, like in the NPDM JSON.
+    pub highest_priority: u32,
+    /// The numerically lowest thread priority allowed, like in the NPDM JSON.
+    pub lowest_priority: u32,
+    /// The lowest CPU core allowed.
+    pub min_core_number: u32,
+    /// The highest CPU core allowed.
+    pub max_core_number: u32,
+    /// The system calls allowed, by name or ID.
+    #[serde(default)]
+    pub enable_system_calls: Vec<SystemCall>,
+    /// The interrupts allowed.
+    #[serde(default)]
+    pub enable_interrupts: Vec<u32>,
+    /// The program type.
+    pub program_type: Option<u32>,
+    /// The minimum kernel version, like `"3.0"`.
+    pub kernel_version: Option<String>,
+    /// The maximum handle table size.
+    pub handle_table_size: Option<u32>,
+    /// Whether debugging is enabled.
+    #[serde(default)]
+    pub enable_debug: bool,
+    /// Whether debugging is forced.
+    #[serde(default)]
+    pub force_debug: bool,
+    /// Other raw capability descriptors (like memory mappings).
+    #[serde(default)]
+    pub descriptors: Vec<u32>,
+}
+
+/// A system call, by name or ID.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SystemCall {
+    Id(u32),
+    Name(String),
+}
+
+impl SystemCall {
+    fn id(&self) -> io::Result<u32> {
+        match self {
+            SystemCall::Id(id) => Ok(*id),
+            SystemCall::Name(name) => SYSTEM_CALLS
+                .iter()
+                .position(|syscall| !syscall.is_empty() && syscall == name)
+                .map(|id| id as u32)
+                .ok_or_else(|| invalid_data(&format!("unknown system call `{}`", name))),
+        }
+    }
+}
+
+impl KernelCapabilities {
+    /// Encode the capabilities as kernel capability descriptors.
+    fn descriptors(&self) -> io::Result<Vec<u32>> {
+        // The numerically highest priority is the one the kernel calls the lowest
+        let mut descriptors = vec![
+            0b111
+                | self.highest_priority << 4
+                | self.lowest_priority << 10
+                | self.min_core_number << 16
+                | self.max_core_number << 24,
+        ];
+
+        // Each descriptor enables the system calls of a group of 24
+        let mut syscall_masks = [0u32; 8];
+        for syscall in &self.enable_system_calls {
+            let id = syscall.id()?;
+            let index = (id / SYSCALLS_PER_DESCRIPTOR) as usize;
+            let mask = syscall_masks
+                .get_mut(index)
+                .ok_or_else(|| invalid_data(&format!("invalid system call ID {:#X}", id)))?;
+            *mask |= 1 << (id % SYSCALLS_PER_DESCRIPTOR);
+        }
+        for (index, mask) in syscall_masks.iter().enumerate() {
+            if *mask != 0 {
+                descriptors.push(0b1111 | mask << 5 | (index as u32) << 29);
+            }
+        }
+
+        // Each descriptor enables two interrupts, 0x3FF being none
+        for pair in self.enable_interrupts.chunks(2) {
+            let second = pair.get(1).copied().unwrap_or(0x3FF);
+            descriptors.push(0x7FF | pair[0] << 12 | second << 22);
+        }
+
+        if let Some(program_type) = self.program_type {
+            descriptors.push(0x1FFF | program_type << 14);
+        }
+        if let Some(kernel_version) = &self.kernel_version {
+            let (major, minor) = kernel_version
+                .split_once('.')
+                .and_then(|(major, minor)| {
+                    Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+                })
+                .ok_or_else(|| {
+                    invalid_data(&format!("invalid kernel version `{}`", kernel_version))
+                })?;
+            descriptors.push(0x3FFF | minor << 15 | major << 19);
+        }
+        if let Some(handle_table_size) = self.handle_table_size {
+            descriptors.push(0x7FFF | handle_table_size << 16);
+        }
+        if self.enable_debug || self.force_debug {
+            descriptors
+                .push(0xFFFF | (self.enable_debug as u32) << 17 | (self.force_debug as u32) << 18);
+        }
+
+        descriptors.extend(&self.descriptors);
+        Ok(descriptors)
+    }
+
+    /// The mask of the CPU cores allowed.
+    fn affinity_mask(&self) -> u32 {
+        (self.min_core_number..=self.max_core_number.min(31)).fold(0, |mask, core| mask | 1 << core)
+    }
+}
+
+/// Write the module as an (uncompressed) KIP, with the specified name, program ID and metadata.
+pub fn write_kip(
+    module: &Module,
+    name: &str,
+    program_id: u64,
+    metadata: &KipMetadata,
+) -> io::Result<Vec<u8>> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid_data(&format!(
+            "the KIP name `{}` is longer than {} bytes",
+            name, MAX_NAME_LEN
+        )));
+    }
+    let ByteSize(stack_size) = metadata.main_thread_stack_size;
+    if stack_size == 0 || stack_size % 0x1000 != 0 || stack_size > u32::MAX as u64 {
+        return Err(invalid_data(
+            "the main thread stack size must be a non-zero multiple of 4KB",
+        ));
+    }
+    let capabilities = &metadata.kernel_capabilities;
+    let priority = metadata.main_thread_priority as u32;
+    if priority < capabilities.lowest_priority || priority > capabilities.highest_priority {
+        return Err(invalid_data(&format!(
+            "the main thread priority {} is outside of the allowed priorities ({}-{})",
+            priority, capabilities.lowest_priority, capabilities.highest_priority
+        )));
+    }
+    let descriptors = capabilities.descriptors()?;
+    if descriptors.len() > MAX_KERNEL_CAPABILITIES {
+        return Err(invalid_data(&format!(
+            "too many kernel capability descriptors ({} > {})",
+            descriptors.len(),
+            MAX_KERNEL_CAPABILITIES
+        )));
+    }
+
+    let mut kip = vec![0u8; KIP_HEADER_SIZE];
+    kip[..KIP_MAGIC.len()].copy_from_slice(KIP_MAGIC);
+    kip[0x4..0x4 + name.len()].copy_from_slice(name.as_bytes());
+    write_u64(&mut kip, 0x10, program_id);
+    write_u32(&mut kip, 0x18, metadata.version);
+    kip[0x1C] = metadata.main_thread_priority;
+    kip[0x1D] = metadata.main_thread_core_number;
+    let flags = &metadata.flags;
+    kip[0x1F] = (flags.is_64_bit as u8) << 3
+        | (flags.address_space_64_bit as u8) << 4
+        | (flags.use_secure_memory as u8) << 5
+        | (flags.immortal as u8) << 6;
+
+    // The segments are stored uncompressed, so their file size is their size
+    for (index, segment) in module.segments.iter().enumerate() {
+        let header = 0x20 + index * 0x10;
+        let size = segment.data.len() as u32;
+        write_u32(&mut kip, header, segment.memory_offset);
+        write_u32(&mut kip, header + 0x4, size);
+        write_u32(&mut kip, header + 0x8, size);
+        kip.extend_from_slice(&segment.data);
+    }
+    write_u32(&mut kip, 0x2C, capabilities.affinity_mask());
+    write_u32(&mut kip, 0x3C, stack_size as u32);
+
+    let data = &module.segments[2];
+    write_u32(&mut kip, 0x50, data.memory_offset + data.data.len() as u32);
+    write_u32(&mut kip, 0x54, module.bss_size);
+
+    for index in 0..MAX_KERNEL_CAPABILITIES {
+        let descriptor = descriptors
+            .get(index)
+            .copied()
+            .unwrap_or(KERNEL_CAPABILITY_EMPTY);
+        write_u32(&mut kip, KERNEL_CAPABILITIES_OFFSET + index * 4, descriptor);
+    }
+
+    Ok(kip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bytes::{read_u32, read_u64},
+        npdm::{self, KernelCapability},
+        nso::Segment,
+    };
+
+    fn test_metadata() -> KipMetadata {
+        serde_json::from_value(serde_json::json!({
+            "program_id": "0100000000000F00",
+            "version": 2,
+            "main_thread_priority": 44,
+            "main_thread_core_number": 3,
+            "main_thread_stack_size": 0x4000,
+            "kernel_capabilities": {
+                "highest_priority": 63,
+                "lowest_priority": 24,
+                "min_core_number": 0,
+                "max_core_number": 3,
+                "enable_system_calls": ["SetHeapSize", "SendSyncRequest", 0x7F],
+                "enable_interrupts": [1, 2, 3],
+                "program_type": 1,
+                "kernel_version": "3.0",
+                "handle_table_size": 512,
+                "enable_debug": true,
+                "descriptors": [0x0000_003F]
+            }
+        }))
+        .unwrap()
+    }
+
+    fn test_module() -> Module {
+        Module {
+            segments: [
+                Segment {
+                    memory_offset: 0,
+                    data: vec![0x11; 0x100],
+                },
+                Segment {
+                    memory_offset: 0x1000,
+                    data: vec![0x22; 0x80],
+                },
+                Segment {
+                    memory_offset: 0x2000,
+                    data: vec![0x33; 0x40],
+                },
+            ],
+            bss_size: 0x1000,
+            module_id: [0; 0x20],
+            rodata_tables: [(0, 0); 3],
+        }
+    }
+
+    #[test]
+    fn kernel_capability_descriptors() {
+        let descriptors = test_metadata().kernel_capabilities.descriptors().unwrap();
+        assert_eq!(
+            descriptors,
+            [
+                0x0300_63F7, // Thread info
+                0x0000_004F, // System calls 0x00-0x17
+                0x2000_400F, // System calls 0x18-0x2F
+                0xA000_100F, // System calls 0x78-0x8F
+                0x0080_17FF, // Interrupts 1 and 2
+                0xFFC0_37FF, // Interrupt 3 and none
+                0x0000_5FFF, // Program type
+                0x0018_3FFF, // Kernel version
+                0x0200_7FFF, // Handle table size
+                0x0002_FFFF, // Debug flags
+                0x0000_003F, // Raw descriptor
+            ]
+        );
+    }
+
+    #[test]
+    fn kernel_capabilities_decode() {
+        let kip = write_kip(&test_module(), "test", 0x0100000000000F00, &test_metadata()).unwrap();
+        let capabilities = npdm::parse_kernel_capabilities(
+            &kip[KERNEL_CAPABILITIES_OFFSET..KERNEL_CAPABILITIES_OFFSET + 0x80],
+        )
+        .unwrap();
+        assert_eq!(
+            capabilities,
+            [
+                KernelCapability::ThreadInfo {
+                    lowest_priority: 63,
+                    highest_priority: 24,
+                    min_core_number: 0,
+                    max_core_number: 3,
+                },
+                KernelCapability::EnableSystemCalls(vec![0x01, 0x21, 0x7F]),
+                KernelCapability::EnableInterrupts(vec![1, 2]),
+                KernelCapability::EnableInterrupts(vec![3]),
+                KernelCapability::MiscParams { program_type: 1 },
+                KernelCapability::KernelVersion { major: 3, minor: 0 },
+                KernelCapability::HandleTableSize(512),
+                KernelCapability::MiscFlags {
+                    enable_debug: true,
+                    force_debug: false,
+                },
+                KernelCapability::Other(0x0000_003F),
+            ]
+        );
+    }
+
+    #[test]
+    fn kip_header() {
+        let module = test_module();
+        let kip = write_kip(&module, "test", 0x0100000000000F00, &test_metadata()).unwrap();
+        assert_eq!(&kip[..4], KIP_MAGIC);
+        assert_eq!(&kip[0x4..0x10], b"test\0\0\0\0\0\0\0\0");
+        assert_eq!(read_u64(&kip, 0x10).unwrap(), 0x0100000000000F00);
+        assert_eq!(read_u32(&kip, 0x18).unwrap(), 2);
+        assert_eq!((kip[0x1C], kip[0x1D], kip[0x1F]), (44, 3, 0b11000));
+        assert_eq!(read_u32(&kip, 0x2C).unwrap(), 0b1111);
+        assert_eq!(read_u32(&kip, 0x3C).unwrap(), 0x4000);
+        assert_eq!(read_u32(&kip, 0x50).unwrap(), 0x2040);
+        assert_eq!(read_u32(&kip, 0x54).unwrap(), 0x1000);
+
+        // The unused descriptors are padding, and the segments follow the header
+        assert_eq!(read_u32(&kip, 0xFC).unwrap(), KERNEL_CAPABILITY_EMPTY);
+        assert_eq!(kip.len(), KIP_HEADER_SIZE + 0x100 + 0x80 + 0x40);
+        assert_eq!(kip[KIP_HEADER_SIZE + 0x100], 0x22);
+    }
+
+    #[test]
+    fn kip_invalid_metadata() {
+        let module = test_module();
+        let program_id = 0x0100000000000F00;
+        assert!(write_kip(&module, "a-much-longer-name", program_id, &test_metadata()).is_err());
+
+        let mut metadata = test_metadata();
+        metadata.main_thread_stack_size = ByteSize(0x1800);
+        assert!(write_kip(&module, "test", program_id, &metadata).is_err());
+
+        let mut metadata = test_metadata();
+        metadata.main_thread_priority = 12;
+        assert!(write_kip(&module, "test", program_id, &metadata).is_err());
+
+        let mut metadata = test_metadata();
+        metadata.kernel_capabilities.enable_system_calls = vec![SystemCall::Name("Unknown".into())];
+        assert!(write_kip(&module, "test", program_id, &metadata).is_err());
+        metadata.kernel_capabilities.enable_system_calls = vec![SystemCall::Id(0xC0)];
+        assert!(write_kip(&module, "test", program_id, &metadata).is_err());
+    }
+}
@@ -7,37 +7,159 @@
 //! See: https://github.com/switchbrew/switch-tools/blob/22756068dd0ed6ff9734c59cb4f99ebd3f62555b/src/nxlink.c
 
 use std::{
-    net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
-    time::Duration,
+    ffi::OsStr,
+    fs::File,
+    io::{self, IsTerminal, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use netloader::loader::send::send_nro_file;
+use netloader::{
+    loader::{
+        discovery::{discover_all, discover_on_ports, DiscoveryMode},
+        send::{
+            send_nro_file_with_progress, CompressionAlgo, SendNroError, SendOptions, TransferStats,
+        },
+    },
+    stdio::ServerEvent,
+};
+
+use crate::{
+    build,
+    config::{self, Config},
+    size::format_size,
+};
+
+/// The default number of times to retry server discovery.
+const DEFAULT_DISCOVERY_RETRIES: u32 = 10;
+
+/// The time to wait for the responses to each server discovery message.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// The time without any file data being sent after which a transfer is considered stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The wait time between attempts to restart a stalled transfer.
+const RESUME_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The initial wait time before restarting the stdio server after a failure.
+const RECONNECT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum wait time before restarting the stdio server after a failure.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 /// The `link` subcommand CLI arguments.
 #[derive(clap::Args)]
 pub struct Args {
-    /// The IP address of the netloader server.
+    /// The IP (v4 or v6) address of the netloader server. Skips server discovery.
     #[arg(short, long, value_parser)]
     pub address: Option<IpAddr>,
-    /// The number of times to retry server discovery.
-    #[arg(short, long, default_value_t = 10)]
-    pub retries: u32,
+    /// The number of times to retry server discovery, and connecting to the server [default: 10].
+    #[arg(short, long)]
+    pub retries: Option<u32>,
+    /// How to send the server discovery message.
+    /// Multicast (IPv4 or IPv6) requires a netloader server listening on the multicast group.
+    #[arg(long, value_enum, default_value = "broadcast")]
+    pub discovery: Discovery,
+    /// The index of the network interface to send the multicast6 discovery message on
+    /// [default: every interface].
+    #[arg(long, value_name = "INDEX")]
+    pub interface: Option<u32>,
+    /// The port the netloader server listens on, for servers rebuilt to use another one
+    /// [default: 28280].
+    #[arg(long, value_name = "PORT")]
+    pub server_port: Option<u16>,
+    /// The port the discovery response and the nxLink stdio connection are received on
+    /// [default: 28771].
+    #[arg(long, value_name = "PORT")]
+    pub client_port: Option<u16>,
+    /// The number of seconds to wait for each acknowledgement of the netloader server
+    /// [default: no timeout, as writing a large file to a slow SD card can take a while].
+    #[arg(long, value_name = "SECS")]
+    pub handshake_timeout: Option<u64>,
+    /// The compression algorithm used to send the file.
+    /// Only deflate is currently supported by the netloader server.
+    #[arg(long, value_enum, default_value = "deflate")]
+    pub compression_algo: Compression,
+    /// Send the file data without compressing it, for maximum speed on fast local networks.
+    #[arg(
+        long,
+        overrides_with = "compress",
+        conflicts_with = "compression_level"
+    )]
+    pub compress_none: bool,
+    /// Compress the file data, even if the configuration file sets `compress_none`.
+    #[arg(long, overrides_with = "compress_none")]
+    pub compress: bool,
+    /// The compression level of the file data, from 0 (no compression) to 9 (best compression)
+    /// [default: 6].
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression_level: Option<u32>,
     /// Set upload path for the file.
     #[arg(short, long, value_parser)]
     pub path: Option<PathBuf>,
+    /// Set upload directory for the file, keeping the original file name.
+    #[arg(short, long, value_name = "DIR", conflicts_with = "path")]
+    pub dest_dir: Option<String>,
+    /// Set the name the switch saves the file as, instead of the local file name.
+    /// The directory of `--path`/`--dest-dir` is kept, if given.
+    #[arg(long, value_name = "NAME")]
+    pub file_name: Option<String>,
     /// Extra arguments to pass to the NRO file.
     #[arg(long = "args", value_name = "ARGS")]
     pub extra_args: Option<String>,
+    /// Pass the destination path on the switch as the first argument (`argv[0]`) to the NRO,
+    /// like the homebrew loader does.
+    #[arg(long)]
+    pub set_argv0: bool,
     /// Start the nxLink stdio server after a successful file transfer.
-    #[arg(short, long, action)]
+    #[arg(short, long, action, overrides_with = "no_server")]
     pub server: bool,
+    /// Don't start the nxLink stdio server, even if the configuration file sets `server`.
+    #[arg(long, action, overrides_with = "server")]
+    pub no_server: bool,
+    /// Restart the nxLink stdio server (with backoff) if it fails, instead of exiting.
+    /// Requires the stdio server to be started (with `--server` or the configuration file).
+    /// Successive connections are always accepted until Ctrl+C.
+    #[arg(long, action)]
+    pub reconnect: bool,
+    /// If the transfer stalls (for instance, because the switch went to sleep), keep retrying it
+    /// until the switch is reachable again.
+    /// Without it, a stalled transfer is awaited indefinitely.
+    #[arg(long, action)]
+    pub resume_on_wake: bool,
+    /// Print a summary of the transfer (sizes, elapsed time and throughput) to stderr.
+    #[arg(long, action)]
+    pub print_speed: bool,
+    /// List the netloader servers discovered in the network instead of sending a file.
+    #[arg(long, action, conflicts_with = "address")]
+    pub list: bool,
     /// NRO file to send to the netloader server.
-    #[arg(value_name = "FILE", value_parser)]
-    pub nro_file: PathBuf,
-    /// Args to send to NRO
+    #[arg(value_name = "FILE", value_parser, required_unless_present = "list")]
+    pub nro_file: Option<PathBuf>,
+    /// Args to send to NRO.
+    ///
+    /// Args after a `--` separator are also sent (like with `nxlink`), even if they start with `-`.
+    /// Both are sent in command-line order, followed by the ones in `--args`.
     #[arg(value_name = "ARGS", value_parser)]
     pub nro_args: Vec<String>,
+    /// The project directory the configuration file and default NRO arguments are read from
+    /// (the current directory if not set).
+    #[arg(skip)]
+    pub project_dir: Option<PathBuf>,
+}
+
+impl Args {
+    /// The arguments to send `nro_file` (built from the project in `project_dir`) with the default
+    /// settings, like `cargo nx link <FILE>`.
+    pub fn for_file(nro_file: &Path, project_dir: &Path) -> Self {
+        let command = <Self as clap::Args>::augment_args(clap::Command::new("link"));
+        let matches = command.get_matches_from([OsStr::new("link"), nro_file.as_os_str()]);
+        let mut args = <Self as clap::FromArgMatches>::from_arg_matches(&matches).unwrap();
+        args.project_dir = Some(project_dir.to_path_buf());
+        args
+    }
 }
 
 /// Handle the `link` subcommand.
@@ -46,13 +168,76 @@ pub async fn handle_subcommand(
     Args {
         address,
         retries,
+        discovery,
+        interface,
+        server_port,
+        client_port,
+        handshake_timeout,
+        compression_algo,
+        compress_none,
+        compress,
+        compression_level,
         path,
+        dest_dir,
+        file_name,
         extra_args,
+        set_argv0,
         server,
+        no_server,
+        reconnect,
+        resume_on_wake,
+        print_speed,
+        list,
         nro_file,
         mut nro_args,
+        project_dir,
     }: Args,
 ) {
+    let project_dir = project_dir.unwrap_or_else(|| PathBuf::from("."));
+
+    // Fill the unspecified arguments with the configured defaults
+    let config = Config::load(&project_dir).link;
+    let address = address.or(config.address);
+    let retries = retries
+        .or(config.retries)
+        .unwrap_or(DEFAULT_DISCOVERY_RETRIES);
+    let server_port = server_port
+        .or(config.server_port)
+        .unwrap_or(netloader::SERVER_PORT);
+    let client_port = client_port
+        .or(config.client_port)
+        .unwrap_or(netloader::CLIENT_PORT);
+    let server = config::resolve_flag(server, no_server, config.server);
+    let compress_none = config::resolve_flag(compress_none, compress, config.compress_none);
+    // An explicit level takes precedence over a configured `compress_none`
+    let compression_level = match compression_level {
+        Some(level) => Some(level),
+        None if compress_none => Some(0),
+        None => config.compression_level,
+    };
+    if let Some(level) = compression_level.filter(|&level| level > 9) {
+        eprintln!(
+            "Invalid configured compression level {} (expected 0 to 9)",
+            level
+        );
+        return;
+    }
+    if reconnect && !server {
+        eprintln!("--reconnect requires the stdio server (--server, or `server = true` in the configuration file)");
+        return;
+    }
+
+    if interface.is_some() && !matches!(discovery, Discovery::Multicast6) {
+        eprintln!("Warning: --interface only applies to multicast6 discovery");
+    }
+    let discovery_mode = discovery.mode(interface);
+
+    if list {
+        list_servers(discovery_mode, retries, server_port, client_port).await;
+        return;
+    }
+
+    let nro_file = nro_file.expect("the NRO file is required unless --list is given");
     tracing::debug!("File path: {}", nro_file.display());
 
     // Check if the file exists
@@ -86,26 +271,25 @@ pub async fn handle_subcommand(
 
     tracing::debug!("NRO file name: {}", nro_file_name);
 
-    // If the path has a `.nro` extension, use it as the destination path
-    // Otherwise, if the path ends with a `/`, join the file name to the path
-    let dest_path = match path {
-        Some(path) => {
-            if path.extension().map_or(false, |ext| ext == "nro") {
-                path.to_str()
-                    .expect("Failed to convert path to string")
-                    .to_string()
-            } else if path.to_str().map_or(false, |path| path.ends_with("/")) {
-                path.join(nro_file_name)
-                    .to_str()
-                    .expect("Failed to convert path to string")
-                    .to_string()
-            } else {
-                eprintln!("Invalid path: {}", path.display());
-                return;
-            }
+    // The on-device file name, if it has to differ from the local one
+    let file_name = match file_name {
+        Some(file_name) if file_name.is_empty() || file_name.contains(['/', '\\']) => {
+            eprintln!("Invalid file name: {}", file_name);
+            return;
         }
-        // Otherwise, use the NRO file name
-        None => nro_file_name,
+        Some(file_name) if file_name.ends_with(".nro") => Some(file_name),
+        Some(file_name) => Some(format!("{}.nro", file_name)),
+        None => None,
+    };
+    let dest_file_name = file_name.as_deref().unwrap_or(&nro_file_name);
+
+    let Some(dest_path) = resolve_dest_path(
+        dest_dir.as_deref(),
+        path.as_deref(),
+        file_name.as_deref(),
+        dest_file_name,
+    ) else {
+        return;
     };
 
     tracing::debug!("Destination path: {}", dest_path);
@@ -138,33 +322,80 @@ pub async fn handle_subcommand(
         }
     }
 
-    // Determine the server IP address
+    // If no arguments were given, use the package's default NRO arguments, if any
+    if nro_args.is_empty() {
+        if let Some(default_args) = read_default_nro_args(&project_dir, &nro_file_name) {
+            tracing::debug!("Using default NRO arguments: {:?}", default_args);
+            nro_args = default_args;
+        }
+    }
+
+    // Prepend the NRO path on the SD card as `argv[0]`, if requested
+    if set_argv0 {
+        nro_args.insert(0, format!("sdmc:/{}", dest_path.trim_start_matches('/')));
+    }
+
+    tracing::debug!("NRO arguments: {:?}", nro_args);
+
+    // Determine the server IP address, skipping discovery if it was given explicitly
     let remote_addr = match address {
-        Some(ip_addr) => (ip_addr, netloader::SERVER_PORT),
-        None => {
-            match netloader::loader::discovery::discover(Duration::from_millis(250), retries).await
-            {
-                Ok(Some(ip_addr)) => (ip_addr, netloader::SERVER_PORT),
-                Ok(None) => {
-                    eprintln!("No server found in the network");
-                    return;
-                }
-                Err(err) => {
-                    eprintln!("Server discovery failed: {}", err);
-                    return;
-                }
+        Some(ip_addr) => (ip_addr, server_port),
+        None => match discover_server(discovery_mode, retries, server_port, client_port).await {
+            Ok(Some(ip_addr)) => (ip_addr, server_port),
+            Ok(None) => {
+                eprintln!("No server found in the network");
+                return;
             }
-        }
+            Err(err) => {
+                eprintln!("Server discovery failed: {}", err);
+                return;
+            }
+        },
     };
 
     println!("Sending file to: {}", remote_addr.0);
 
+    let send_options = SendOptions {
+        ack_timeout: handshake_timeout.map(Duration::from_secs),
+        // Like the acknowledgements, a transfer which stops progressing is awaited indefinitely
+        // unless it has to be resumed
+        stall_timeout: resume_on_wake.then_some(STALL_TIMEOUT),
+        compression: match compression_algo {
+            Compression::Deflate => CompressionAlgo::Deflate,
+        },
+        compression_level,
+        connect_retries: retries,
+    };
+
     // Send the file to the remote server
+    let send_start = Instant::now();
+    let send_fut = send_file(
+        remote_addr,
+        &dest_path,
+        &mut file,
+        file_length,
+        &nro_args,
+        &send_options,
+        resume_on_wake,
+    );
     tokio::select! {biased;
-        res = send_nro_file(remote_addr, &dest_path, &mut file, file_length, nro_args) => {
+        res = send_fut => {
             match res {
-                Ok(_) => {
-                    println!("File sent successfully");
+                Ok(stats) => {
+                    println!(
+                        "File sent successfully: {} on the SD card ({} transferred compressed)",
+                        format_size(stats.file_length as u64),
+                        format_size(stats.compressed_length)
+                    );
+                    println!("CRC32 of the sent file data: {:08x}", stats.crc32);
+
+                    if print_speed {
+                        print_transfer_summary(
+                            stats.file_length as u64,
+                            stats.compressed_length,
+                            send_start.elapsed(),
+                        );
+                    }
                 }
                 Err(err) => {
                     eprintln!("Failed to send the file: {err}");
@@ -180,14 +411,382 @@ pub async fn handle_subcommand(
     if server {
         println!("Starting the nxlink stdio server. Press Ctrl+C to exit.");
 
-        let stdio_server_addr = (Ipv4Addr::UNSPECIFIED, netloader::CLIENT_PORT);
+        // The server connects back over the same IP version the file was sent with
+        let stdio_server_ip = match remote_addr.0 {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let stdio_server_addr = (stdio_server_ip, client_port);
         tokio::select! {biased;
-            _ = netloader::stdio::start_server(stdio_server_addr) => {}
+            _ = run_stdio_server(stdio_server_addr, reconnect) => {}
             _ = tokio::signal::ctrl_c() => {}
         }
     }
 }
 
+/// Send the file to the netloader server.
+///
+/// If `resume_on_wake` is set and the transfer stalls, it is restarted from the beginning (the
+/// protocol can't resume a partial transfer) until it succeeds or fails for any other reason.
+async fn send_file(
+    remote_addr: (IpAddr, u16),
+    dest_path: &str,
+    file: &mut File,
+    file_length: usize,
+    nro_args: &[String],
+    options: &SendOptions,
+    resume_on_wake: bool,
+) -> io::Result<TransferStats> {
+    let mut waiting_for_wake = false;
+    loop {
+        // The clone shares the file position, so each attempt reads the file from the start
+        file.seek(SeekFrom::Start(0))?;
+        let mut progress = TransferProgress::new();
+        let res = send_nro_file_with_progress(
+            remote_addr,
+            dest_path,
+            file.try_clone()?,
+            file_length,
+            nro_args,
+            options,
+            |sent, total| progress.update(sent, total),
+        )
+        .await;
+        progress.finish();
+
+        // Once stalled, the switch is unreachable until it wakes up, so keep retrying on any error
+        // other than the server rejecting the file
+        match res {
+            Err(err)
+                if resume_on_wake
+                    && (is_transfer_stalled(&err)
+                        || (waiting_for_wake && !is_server_rejection(&err))) =>
+            {
+                if !waiting_for_wake {
+                    eprintln!("{err}, waiting for it to wake up to retry...");
+                    waiting_for_wake = true;
+                }
+                tracing::debug!("Transfer retry failed: {}", err);
+
+                tokio::time::sleep(RESUME_RETRY_INTERVAL).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// The width of the transfer progress bar, in characters.
+const PROGRESS_BAR_WIDTH: usize = 30;
+
+/// The transfer progress step between the percentage lines printed when stdout isn't a terminal.
+const PROGRESS_LINE_STEP: u64 = 10;
+
+/// The file transfer progress indicator.
+///
+/// It's a progress bar (cleared on completion) when stdout is a terminal, and periodic percentage
+/// lines otherwise.
+struct TransferProgress {
+    is_terminal: bool,
+    last_percent: Option<u64>,
+}
+
+impl TransferProgress {
+    fn new() -> Self {
+        Self {
+            is_terminal: io::stdout().is_terminal(),
+            last_percent: None,
+        }
+    }
+
+    /// Update the progress with the number of bytes sent so far.
+    fn update(&mut self, sent: u64, total: u64) {
+        let percent = (sent * 100).checked_div(total).unwrap_or(100).min(100);
+        if self.is_terminal {
+            let filled = percent as usize * PROGRESS_BAR_WIDTH / 100;
+            print!(
+                "\r[{}{}] {:>3}% ({} / {})",
+                "#".repeat(filled),
+                "-".repeat(PROGRESS_BAR_WIDTH - filled),
+                percent,
+                format_size(sent),
+                format_size(total)
+            );
+            let _ = io::stdout().flush();
+        } else {
+            let due = match self.last_percent {
+                Some(last) => {
+                    percent >= last + PROGRESS_LINE_STEP || (percent == 100 && last != 100)
+                }
+                None => true,
+            };
+            if !due {
+                return;
+            }
+            println!("{}% sent", percent);
+        }
+        self.last_percent = Some(percent);
+    }
+
+    /// Clear the progress bar, if any was shown.
+    fn finish(&mut self) {
+        if self.is_terminal && self.last_percent.is_some() {
+            print!("\r\x1b[2K");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+/// Check whether a transfer error is caused by the transfer stalling.
+fn is_transfer_stalled(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<SendNroError>()),
+        Some(SendNroError::TransferStalled { .. })
+    )
+}
+
+/// Check whether a transfer error is an error code returned by the netloader server.
+fn is_server_rejection(err: &io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<SendNroError>()),
+        Some(
+            SendNroError::CouldNotCreateFile
+                | SendNroError::InsufficientSpace
+                | SendNroError::FileExtensionNotRecognized
+                | SendNroError::UnknownError(_)
+        )
+    )
+}
+
+/// The server discovery method.
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum Discovery {
+    Broadcast,
+    Multicast,
+    Multicast6,
+}
+
+impl Discovery {
+    /// The discovery mode, sending the IPv6 multicast message on `interface` (or every one).
+    fn mode(self, interface: Option<u32>) -> DiscoveryMode {
+        match self {
+            Discovery::Broadcast => DiscoveryMode::Broadcast,
+            Discovery::Multicast => DiscoveryMode::Multicast,
+            Discovery::Multicast6 => DiscoveryMode::MulticastV6 { interface },
+        }
+    }
+}
+
+/// The file compression algorithm.
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum Compression {
+    Deflate,
+}
+
+/// Print a one-line summary of a completed transfer to stderr.
+///
+/// Both the over-the-wire (compressed) and the effective (original bytes) throughput are shown.
+fn print_transfer_summary(file_length: u64, compressed_length: u64, elapsed: Duration) {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    eprintln!(
+        "Transferred {} ({} compressed) in {:.2}s: {}/s over the wire, {}/s effective",
+        format_size(file_length),
+        format_size(compressed_length),
+        elapsed.as_secs_f64(),
+        format_size((compressed_length as f64 / secs) as u64),
+        format_size((file_length as f64 / secs) as u64)
+    );
+}
+
+/// Discover the netloader server in the network.
+///
+/// If multicast discovery gets no response, broadcast discovery is attempted next, since the
+/// netloader server may not be listening on the multicast group.
+async fn discover_server(
+    mode: DiscoveryMode,
+    retries: u32,
+    server_port: u16,
+    client_port: u16,
+) -> std::io::Result<Option<IpAddr>> {
+    let res = discover_on_ports(DISCOVERY_TIMEOUT, retries, mode, server_port, client_port).await;
+    match res {
+        Ok(None) if mode != DiscoveryMode::Broadcast => {
+            eprintln!("No server responded to multicast discovery, trying broadcast...");
+            discover_on_ports(
+                DISCOVERY_TIMEOUT,
+                retries,
+                DiscoveryMode::Broadcast,
+                server_port,
+                client_port,
+            )
+            .await
+        }
+        res => res,
+    }
+}
+
+/// Print the netloader servers which respond to discovery in the network.
+async fn list_servers(mode: DiscoveryMode, retries: u32, server_port: u16, client_port: u16) {
+    match discover_all(DISCOVERY_TIMEOUT, retries, mode, server_port, client_port).await {
+        Ok(servers) => {
+            for server in &servers {
+                println!("{}", server);
+            }
+            println!("{} server(s) responded", servers.len());
+        }
+        Err(err) => eprintln!("Server discovery failed: {}", err),
+    }
+}
+
+/// Run the nxlink stdio server, accepting successive connections until Ctrl+C.
+///
+/// If `reconnect` is set, the server is restarted every time it fails (for instance, if the port
+/// can't be bound), waiting with an exponential backoff after each failure.
+async fn run_stdio_server(addr: (IpAddr, u16), reconnect: bool) {
+    let mut backoff = RECONNECT_MIN_BACKOFF;
+    let mut attempt: u64 = 0;
+    loop {
+        attempt += 1;
+        let res = netloader::stdio::start_server_with_events(addr, |event| match event {
+            ServerEvent::Connected(peer_addr) => {
+                backoff = RECONNECT_MIN_BACKOFF;
+                println!("Connection opened by {peer_addr}");
+            }
+            ServerEvent::Disconnected {
+                peer_addr,
+                error: None,
+            } => println!(
+                "Connection with {peer_addr} closed, waiting for the switch to reconnect..."
+            ),
+            ServerEvent::Disconnected {
+                peer_addr,
+                error: Some(err),
+            } => eprintln!(
+                "Connection with {peer_addr} failed: {err}, waiting for the switch to reconnect..."
+            ),
+        })
+        .await;
+
+        // The server only returns if it fails
+        let Err(err) = res else { continue };
+        if !reconnect {
+            eprintln!("Stdio server error: {err}");
+            return;
+        }
+        eprintln!(
+            "Stdio server error: {err}, retrying in {}s (attempt {attempt})...",
+            backoff.as_secs()
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+    }
+}
+
+/// Read the default NRO arguments (`/nx/nro/args`) of the package in the project directory.
+///
+/// The package is the one with a target named after the NRO file, so an unrelated NRO doesn't get
+/// the arguments of another package.
+fn read_default_nro_args(project_dir: &Path, nro_file_name: &str) -> Option<Vec<String>> {
+    let metadata = build::read_metadata(project_dir).ok()?;
+
+    let target_name = nro_file_name.strip_suffix(".nro").unwrap_or(nro_file_name);
+    // The NRO of a `cdylib` is named after its shared library
+    let package = metadata.packages.iter().find(|package| {
+        package.targets.iter().any(|target| {
+            target.name == target_name
+                || format!("lib{}", target.name.replace('-', "_")) == target_name
+        })
+    })?;
+
+    let default_args = package.metadata.pointer("/nx/nro/args")?.clone();
+    serde_json::from_value(default_args).ok()
+}
+
+/// Get the destination path of the file on the switch SD card, or `None` (after printing the
+/// error) if the given path is invalid.
+///
+/// The paths given with `--path`/`--dest-dir` are normalized, while the default one is the bare
+/// file name.
+fn resolve_dest_path(
+    dest_dir: Option<&str>,
+    path: Option<&Path>,
+    file_name: Option<&str>,
+    dest_file_name: &str,
+) -> Option<String> {
+    // If a destination directory was given, join the file name to it
+    // If the path has a `.nro` extension, use it as the destination path (replacing its file name
+    // with the one given with `--file-name`, if any)
+    // Otherwise, if the path ends with a `/`, join the file name to the path
+    let dest_path = match (dest_dir, path) {
+        (Some(dest_dir), _) => format!("{}/{}", dest_dir.trim_end_matches('/'), dest_file_name),
+        (None, Some(path)) => {
+            if path.extension().map_or(false, |ext| ext == "nro") {
+                let path = path.to_str().expect("Failed to convert path to string");
+                match (path.rsplit_once('/'), file_name) {
+                    (Some((dir, _)), Some(file_name)) => format!("{}/{}", dir, file_name),
+                    (None, Some(file_name)) => file_name.to_string(),
+                    (_, None) => path.to_string(),
+                }
+            } else if path.to_str().map_or(false, |path| path.ends_with("/")) {
+                format!(
+                    "{}{}",
+                    path.to_str().expect("Failed to convert path to string"),
+                    dest_file_name
+                )
+            } else {
+                eprintln!("Invalid path: {}", path.display());
+                return None;
+            }
+        }
+        // Otherwise, use the NRO file name
+        (None, None) => return Some(dest_file_name.to_string()),
+    };
+
+    // Warn if the destination looks like a host path, then normalize it for the switch
+    if looks_like_host_path(&dest_path) {
+        eprintln!(
+            "Warning: the destination path looks like a host path, not a switch path: {}",
+            dest_path
+        );
+    }
+    Some(normalize_dest_path(&dest_path))
+}
+
+/// Normalize the destination path on the switch SD card.
+///
+/// The _netloader_ server prepends the SD card root itself, so an `sdmc:` prefix is stripped.
+/// Backslashes are converted to forward slashes, repeated slashes are collapsed and relative
+/// paths are made absolute (relative to the SD card root).
+fn normalize_dest_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = path.strip_prefix("sdmc:").unwrap_or(&path);
+
+    let mut result = String::with_capacity(path.len() + 1);
+    result.push('/');
+    for c in path.chars() {
+        if c == '/' && result.ends_with('/') {
+            continue;
+        }
+        result.push(c);
+    }
+
+    result
+}
+
+/// Check whether the destination path looks like a host path (e.g. `C:\...`) by mistake.
+fn looks_like_host_path(path: &str) -> bool {
+    let mut chars = path.chars();
+    let has_drive_letter = matches!(
+        (chars.next(), chars.next(), chars.next()),
+        (Some(drive), Some(':'), Some('\\' | '/')) if drive.is_ascii_alphabetic()
+    );
+
+    has_drive_letter || path.contains('\\')
+}
+
 /// Parse the extra arguments CLI string into a vector of arguments.
 fn parse_extra_args(args: String) -> Vec<String> {
     let mut args_chars = args.trim().chars();
@@ -230,3 +829,43 @@ fn parse_extra_args(args: String) -> Vec<String> {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_dest_path() {
+        assert_eq!(
+            resolve_dest_path(None, None, None, "app.nro").as_deref(),
+            Some("app.nro")
+        );
+        assert_eq!(
+            resolve_dest_path(Some("switch"), None, None, "app.nro").as_deref(),
+            Some("/switch/app.nro")
+        );
+        assert_eq!(
+            resolve_dest_path(None, Some(Path::new("switch/")), None, "app.nro").as_deref(),
+            Some("/switch/app.nro")
+        );
+        assert_eq!(
+            resolve_dest_path(None, Some(Path::new("switch")), None, "app.nro"),
+            None
+        );
+    }
+
+    #[test]
+    fn dest_path_normalization() {
+        assert_eq!(normalize_dest_path("app.nro"), "/app.nro");
+        assert_eq!(normalize_dest_path("switch/app.nro"), "/switch/app.nro");
+        assert_eq!(normalize_dest_path("/switch//app.nro"), "/switch/app.nro");
+        assert_eq!(
+            normalize_dest_path("sdmc:/switch/app.nro"),
+            "/switch/app.nro"
+        );
+        assert_eq!(
+            normalize_dest_path("sdmc:switch\\app.nro"),
+            "/switch/app.nro"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Build log file.
+//!
+//! When a log file is opened, the messages printed with [`log_println`] and [`log_eprintln`] (and
+//! panic messages) are also written to it, without ANSI escape sequences, so a build leaves a
+//! persistent record of its output.
+//!
+//! The lines of [`log_println`] can also be redirected to stderr, so that stdout only carries
+//! machine-readable output (like the cargo JSON messages forwarded with `--passthrough-json`).
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, Once,
+    },
+};
+
+/// The open log file, if any.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Whether the lines of [`log_println`] are printed to stderr instead of stdout.
+static STDOUT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Print a line to stdout (or stderr, if redirected), also writing it to the log file if open.
+macro_rules! log_println {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        if $crate::log_file::stdout_redirected() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+        $crate::log_file::write_line(&line);
+    }};
+}
+pub(crate) use log_println;
+
+/// Print a line to stderr, also writing it to the log file if open.
+macro_rules! log_eprintln {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        eprintln!("{}", line);
+        $crate::log_file::write_line(&line);
+    }};
+}
+pub(crate) use log_eprintln;
+
+/// The installation of the panic hook writing the panic messages to the log file.
+static PANIC_HOOK: Once = Once::new();
+
+/// Open the log file at `path`, truncating it unless `append` is set.
+///
+/// Panic messages are also written to the log file from then on (the hook writing them is only
+/// installed once, even if another log file is opened later).
+pub fn open(path: &Path, append: bool) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+
+    PANIC_HOOK.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            write_line(&info.to_string());
+            default_hook(info);
+        }));
+    });
+    Ok(())
+}
+
+/// Print the lines of [`log_println`] to stderr instead of stdout from now on.
+pub fn redirect_stdout_to_stderr() {
+    STDOUT_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Whether the lines of [`log_println`] are printed to stderr instead of stdout.
+pub fn stdout_redirected() -> bool {
+    STDOUT_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// Write a line to the log file if open, without its ANSI escape sequences.
+pub fn write_line(line: &str) {
+    if let Ok(mut log_file) = LOG_FILE.lock() {
+        if let Some(file) = log_file.as_mut() {
+            let _ = writeln!(file, "{}", strip_ansi_escapes(line));
+        }
+    }
+}
+
+/// Remove the ANSI escape sequences (like colors) from `text`.
+fn strip_ansi_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the control sequence, up to its final byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
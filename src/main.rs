@@ -1,8 +1,23 @@
 use clap::Parser as _;
 use tracing_subscriber::EnvFilter;
+mod assets;
 mod build;
+mod bytes;
+mod clean;
+mod config;
+mod convert;
+mod extract;
+mod info;
+mod kip;
 mod link;
+mod log_file;
 mod new;
+mod npdm;
+mod nro;
+mod nso;
+mod pfs0;
+mod run;
+mod size;
 
 fn main() {
     // Set up the logger
@@ -10,12 +25,39 @@ fn main() {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
+    // cargo is run from the project directory, but a relative `CARGO_TARGET_DIR` is expected to be
+    // relative to the current one
+    if let (Some(target_dir), Ok(current_dir)) = (
+        std::env::var_os("CARGO_TARGET_DIR"),
+        std::env::current_dir(),
+    ) {
+        std::env::set_var("CARGO_TARGET_DIR", current_dir.join(target_dir));
+    }
+
     // Parse the command-line arguments and handle the subcommand
     let Cargo::Nx(args) = Cargo::parse();
     match args.subcommand {
-        CargoNxSubcommand::New(args) => new::handle_subcommand(args),
-        CargoNxSubcommand::Build(args) => build::handle_subcommand(args),
+        CargoNxSubcommand::New(args) => exit_on_error(new::handle_subcommand(args)),
+        CargoNxSubcommand::Build(args) => {
+            if let Err(err) = build::handle_subcommand(args) {
+                log_file::log_eprintln!("Error: {}", err);
+                std::process::exit(err.exit_code());
+            }
+        }
+        CargoNxSubcommand::Run(args) => run::handle_subcommand(args),
         CargoNxSubcommand::Link(args) => link::handle_subcommand(args),
+        CargoNxSubcommand::Extract(args) => extract::handle_subcommand(args),
+        CargoNxSubcommand::Clean(args) => clean::handle_subcommand(args),
+        CargoNxSubcommand::Convert(args) => convert::handle_subcommand(args),
+        CargoNxSubcommand::Info(args) => info::handle_subcommand(args),
+    }
+}
+
+/// Print the error of a failed subcommand (also to the log file, if open) and exit.
+fn exit_on_error(result: Result<(), impl std::fmt::Display>) {
+    if let Err(err) = result {
+        log_file::log_eprintln!("Error: {}", err);
+        std::process::exit(1);
     }
 }
 
@@ -38,6 +80,28 @@ enum CargoNxSubcommand {
     New(new::Args),
     #[command(about = "Build a Rust project for the Nintendo Switch")]
     Build(build::Args),
+    #[command(about = "Build a Rust project and run it in a Nintendo Switch emulator")]
+    Run(run::Args),
     #[command(about = "Send a file to the Nintendo Switch")]
     Link(link::Args),
+    #[command(about = "Extract the assets embedded in an NRO file")]
+    Extract(extract::Args),
+    #[command(about = "Remove the files generated by the build subcommand")]
+    Clean(clean::Args),
+    #[command(about = "Convert an NRO file into an NSP file, or the other way around")]
+    Convert(convert::Args),
+    #[command(about = "Print information about an NRO, NSP, NSO or KIP file")]
+    Info(info::Args),
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory as _;
+
+    use super::*;
+
+    #[test]
+    fn cli() {
+        Cargo::command().debug_assert();
+    }
 }
@@ -1,4 +1,10 @@
-use std::{fmt, path::PathBuf};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::build;
 
 const INITIAL_VERSION: &str = "0.1.0";
 
@@ -6,11 +12,14 @@ const DEFAULT_AUTHOR: &str = "aarch64-switch-rs authors";
 
 const DEFAULT_PROGRAM_ID: u64 = 0x0100AAAABBBBCCCC;
 
+/// The program IDs reserved for the system titles.
+const SYSTEM_PROGRAM_IDS: std::ops::RangeInclusive<u64> = 0x0100000000000000..=0x01000000000FFFFF;
+
 /// The supported Rust editions
-const SUPPORTED_EDITIONS: &[&str] = &["2015", "2018", "2021"];
+const SUPPORTED_EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
 
 /// Which Rust edition to use by default
-const DEFAULT_EDITION: &str = "2021";
+const DEFAULT_EDITION: &str = "2024";
 
 /// Which package type to use by default
 const DEFAULT_PACKAGE_TYPE: &str = "nro";
@@ -23,10 +32,29 @@ const DEFAULT_NRO_CARGO_TOML: &str = include_str!("../default/nro/Cargo.toml");
 const DEFAULT_NRO_CARGO_CONFIG_TOML: &str = include_str!("../default/nro/.cargo/config.toml");
 
 const DEFAULT_NRO_SRC_MAIN_RS: &str = include_str!("../default/nro/src/main.rs");
+const DEFAULT_NRO_ROMFS_HELLO_TXT: &str = include_str!("../default/nro/romfs/hello.txt");
 const DEFAULT_NSP_CARGO_TOML: &str = include_str!("../default/nsp/Cargo.toml");
 const DEFAULT_NSP_CARGO_CONFIG_TOML: &str = include_str!("../default/nsp/.cargo/config.toml");
 
 const DEFAULT_NSP_SRC_MAIN_RS: &str = include_str!("../default/nsp/src/main.rs");
+const DEFAULT_OVL_CARGO_TOML: &str = include_str!("../default/ovl/Cargo.toml");
+const DEFAULT_OVL_CARGO_CONFIG_TOML: &str = include_str!("../default/ovl/.cargo/config.toml");
+
+const DEFAULT_OVL_SRC_MAIN_RS: &str = include_str!("../default/ovl/src/main.rs");
+const DEFAULT_KIP_CARGO_TOML: &str = include_str!("../default/kip/Cargo.toml");
+const DEFAULT_KIP_CARGO_CONFIG_TOML: &str = include_str!("../default/kip/.cargo/config.toml");
+
+const DEFAULT_KIP_SRC_MAIN_RS: &str = include_str!("../default/kip/src/main.rs");
+
+/// The `.gitignore` of every package type (named without the dot so it doesn't apply to this
+/// repository).
+const DEFAULT_GITIGNORE: &str = include_str!("../default/gitignore");
+
+/// The `nx` dependency source used by the default manifests.
+const NX_DEPENDENCY_SOURCE: &str = "git = \"https://github.com/aarch64-switch-rs/nx\"";
+
+/// The NRO metadata table the romfs directory is declared before.
+const NRO_NACP_METADATA_TABLE: &str = "[package.metadata.nx.nro.nacp]";
 
 /// The `new` subcommand CLI arguments.
 #[derive(clap::Args)]
@@ -42,94 +70,378 @@ pub struct Args {
     /// The path directory name is used by default.
     #[arg(short, long)]
     pub name: Option<String>,
+    /// Set the author of the newly created package.
+    /// The git user (`GIT_AUTHOR_NAME`/`GIT_AUTHOR_EMAIL` or the git config) is used by default.
+    #[arg(long)]
+    pub author: Option<String>,
+    /// Set the program ID (hex, like `0x0100AAAABBBBCCCC`) of the newly created package.
+    #[arg(long, value_name = "ID", value_parser = parse_program_id)]
+    pub program_id: Option<u64>,
+    /// Create a sample `romfs` directory and embed it in the NRO (only for NRO packages).
+    #[arg(long)]
+    pub with_romfs: bool,
+    /// Initialize a version control repository (with an initial commit) in the project.
+    #[arg(long, value_enum, default_value = "git")]
+    pub vcs: Vcs,
+    /// Add the specified remote as `origin` of the git repository.
+    #[arg(long, value_name = "URL")]
+    pub git_remote: Option<String>,
+    /// Select the overlay framework whose skeleton is used (only for overlay packages).
+    /// The raw skeleton is used by default.
+    #[arg(long, value_enum, value_name = "FRAMEWORK")]
+    pub overlay_framework: Option<OverlayFramework>,
+    /// The `nx` dependency to use: a version requirement (like `0.1`), or a git `branch:<name>`,
+    /// `tag:<name>` or `rev:<hash>` of its repository.
+    /// The git repository's default branch is used by default.
+    #[arg(long, value_name = "REQ", value_parser = parse_nx_version)]
+    pub nx_version: Option<NxVersion>,
+    /// Print the files that would be created and the resolved package values, without creating
+    /// anything.
+    #[arg(long)]
+    pub dry_run: bool,
     /// The path where the new package will be created
     #[arg(value_parser, value_name = "DIR")]
     pub path: PathBuf,
 }
 
+/// An error that makes the package creation fail.
+#[derive(Debug, thiserror::Error)]
+pub enum NewError {
+    /// The project path already exists.
+    #[error("the path {} already exists", .0.display())]
+    PathExists(PathBuf),
+
+    /// The project path has no file name to use as the package name.
+    #[error("the path {} has no valid (UTF-8) file name to use as the package name, specify one with --name", .0.display())]
+    InvalidPathName(PathBuf),
+
+    /// `--with-romfs` was specified for a package that isn't an NRO.
+    #[error("RomFs directories are only supported for NRO packages")]
+    RomFsRequiresNro,
+
+    /// `--overlay-framework` was specified for a package that isn't an overlay.
+    #[error("an overlay framework can only be selected for overlay (`ovl`) packages")]
+    OverlayFrameworkRequiresOvl,
+
+    /// The selected overlay framework has no skeleton yet.
+    #[error(
+        "the {0} overlay framework isn't supported yet, as no Rust bindings are available for it"
+    )]
+    UnsupportedOverlayFramework(OverlayFramework),
+
+    /// A project file or directory couldn't be created.
+    #[error("failed to create {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
 /// Handle the `new` subcommand.
-pub fn handle_subcommand(args: Args) {
-    if args.path.is_dir() {
-        panic!("Specified path already exists...");
+pub fn handle_subcommand(args: Args) -> Result<(), NewError> {
+    if args.path.exists() {
+        return Err(NewError::PathExists(args.path));
+    }
+    if args.with_romfs && !matches!(args.kind, PackageKind::Nro) {
+        return Err(NewError::RomFsRequiresNro);
+    }
+    if args.git_remote.is_some() && args.vcs == Vcs::None {
+        eprintln!(
+            "Warning: --git-remote has no effect with `--vcs none`, as no repository is created"
+        );
+    }
+    if args.overlay_framework.is_some() && !matches!(args.kind, PackageKind::Ovl) {
+        return Err(NewError::OverlayFrameworkRequiresOvl);
+    }
+    if let Some(framework @ OverlayFramework::Libtesla) = args.overlay_framework {
+        return Err(NewError::UnsupportedOverlayFramework(framework));
     }
 
-    let name = args.name.as_deref().unwrap_or_else(|| {
-        args.path
+    let name = match args.name.as_deref() {
+        Some(name) => name,
+        None => args
+            .path
             .file_name()
-            .expect("path has invalid file name")
-            .to_str()
-            .expect("path file name is not valid UTF-8")
-    });
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| NewError::InvalidPathName(args.path.clone()))?,
+    };
     let edition = args
         .edition
         .parse::<u16>()
         .expect("invalid edition. how did this even happen??");
     let version = INITIAL_VERSION;
-    let author = DEFAULT_AUTHOR;
-    let program_id = DEFAULT_PROGRAM_ID;
+    let author = args
+        .author
+        .clone()
+        .or_else(git_author)
+        .unwrap_or_else(|| DEFAULT_AUTHOR.to_string());
+    let author = author.as_str();
+    let nacp_author = nacp_author(author);
+    let program_id = args.program_id.unwrap_or(DEFAULT_PROGRAM_ID);
+    if matches!(args.kind, PackageKind::Nsp) && SYSTEM_PROGRAM_IDS.contains(&program_id) {
+        eprintln!(
+            "Warning: the program ID 0x{:016X} is in the range reserved for system titles (0x{:016X}-0x{:016X})",
+            program_id,
+            SYSTEM_PROGRAM_IDS.start(),
+            SYSTEM_PROGRAM_IDS.end()
+        );
+    }
     let info = PackageInfo {
         name,
         edition,
         version,
         author,
+        nacp_author,
         program_id,
     };
 
-    std::fs::create_dir_all(&args.path).expect("failed to create project directory");
-
     let cargo_toml = match args.kind {
         PackageKind::Lib => DEFAULT_LIB_CARGO_TOML,
         PackageKind::Nro => DEFAULT_NRO_CARGO_TOML,
         PackageKind::Nsp => DEFAULT_NSP_CARGO_TOML,
+        PackageKind::Ovl => DEFAULT_OVL_CARGO_TOML,
+        PackageKind::Kip => DEFAULT_KIP_CARGO_TOML,
     };
     let cargo_config_toml = match args.kind {
         PackageKind::Lib => DEFAULT_LIB_CARGO_CONFIG_TOML,
         PackageKind::Nro => DEFAULT_NRO_CARGO_CONFIG_TOML,
         PackageKind::Nsp => DEFAULT_NSP_CARGO_CONFIG_TOML,
+        PackageKind::Ovl => DEFAULT_OVL_CARGO_CONFIG_TOML,
+        PackageKind::Kip => DEFAULT_KIP_CARGO_CONFIG_TOML,
     };
     let src_main_file = match args.kind {
         PackageKind::Lib => DEFAULT_LIB_SRC_LIB_RS,
         PackageKind::Nro => DEFAULT_NRO_SRC_MAIN_RS,
         PackageKind::Nsp => DEFAULT_NSP_SRC_MAIN_RS,
+        PackageKind::Ovl => DEFAULT_OVL_SRC_MAIN_RS,
+        PackageKind::Kip => DEFAULT_KIP_SRC_MAIN_RS,
     };
 
-    let cargo_toml = process_default_file(cargo_toml, &info);
-    std::fs::write(args.path.join("Cargo.toml"), cargo_toml)
-        .expect("failed to create project Cargo.toml");
+    let mut cargo_toml = process_default_file(cargo_toml, &info);
+    if let Some(nx_version) = &args.nx_version {
+        cargo_toml = cargo_toml.replace(NX_DEPENDENCY_SOURCE, &nx_version.dependency_source());
+    }
+    if args.with_romfs {
+        cargo_toml = cargo_toml.replace(
+            NRO_NACP_METADATA_TABLE,
+            &format!(
+                "[package.metadata.nx.nro]\nromfs = \"romfs\"\n\n{}",
+                NRO_NACP_METADATA_TABLE
+            ),
+        );
+    }
 
-    let dot_cargo_path = args.path.join(".cargo");
-    std::fs::create_dir(dot_cargo_path.clone()).expect("failed to create project .cargo directory");
+    let main_file_path = match args.kind {
+        PackageKind::Lib => "src/lib.rs",
+        PackageKind::Nro | PackageKind::Nsp | PackageKind::Ovl | PackageKind::Kip => "src/main.rs",
+    };
 
-    let cargo_config_toml = process_default_file(cargo_config_toml, &info);
-    std::fs::write(dot_cargo_path.join("config.toml"), cargo_config_toml)
-        .expect("failed to write to project .cargo/config.toml");
+    // The files to create, relative to the project directory
+    let mut files = vec![
+        ("Cargo.toml", cargo_toml),
+        (
+            ".cargo/config.toml",
+            process_default_file(cargo_config_toml, &info),
+        ),
+        (main_file_path, process_default_file(src_main_file, &info)),
+        (".gitignore", process_default_file(DEFAULT_GITIGNORE, &info)),
+    ];
+    if args.with_romfs {
+        files.push(("romfs/hello.txt", DEFAULT_NRO_ROMFS_HELLO_TXT.to_string()));
+    }
 
-    let src_path = args.path.join("src");
-    std::fs::create_dir(&src_path).expect("failed to create project src directory");
+    if args.dry_run {
+        print_dry_run(&args.path, &info, &files);
+        match (args.vcs, &args.git_remote) {
+            (Vcs::Git, Some(git_remote)) => println!(
+                "A git repository would be initialized with `{}` as origin",
+                git_remote
+            ),
+            (Vcs::Git, None) => println!("A git repository would be initialized"),
+            (Vcs::None, _) => {}
+        }
+        return Ok(());
+    }
 
-    let main_file_path = match args.kind {
-        PackageKind::Lib => src_path.join("lib.rs"),
-        PackageKind::Nro | PackageKind::Nsp => src_path.join("main.rs"),
+    let io_error = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| NewError::Io { path, source }
     };
+    std::fs::create_dir_all(&args.path).map_err(io_error(&args.path))?;
+    for (file_path, contents) in &files {
+        let file_path = args.path.join(file_path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent).map_err(io_error(parent))?;
+        }
+        std::fs::write(&file_path, contents).map_err(io_error(&file_path))?;
+    }
 
-    let src_lib_rs = process_default_file(src_main_file, &info);
-    std::fs::write(&main_file_path, src_lib_rs).expect("failed to create project lib/main file");
+    if let Vcs::Git = args.vcs {
+        setup_git_repository(&args.path, args.git_remote.as_deref());
+    }
 
     println!("Created `{}` package ({})", info.name, args.kind);
+    Ok(())
+}
+
+/// Print the resolved package values and the files that would be created, without creating them.
+fn print_dry_run(path: &Path, info: &PackageInfo<'_>, files: &[(&str, String)]) {
+    println!("Package values:");
+    println!("  name: {}", info.name);
+    println!("  author: {}", info.author);
+    println!("  NACP author: {}", info.nacp_author);
+    println!("  version: {}", info.version);
+    println!("  edition: {}", info.edition);
+    println!("  program ID: 0x{:016X}", info.program_id);
+
+    println!("Files that would be created in {}:", path.display());
+    for (file_path, contents) in files {
+        println!();
+        println!("--- {} ---", file_path);
+        println!("{}", contents);
+    }
+}
+
+/// Get the git user as a package author (`Name <email>`), from the `GIT_AUTHOR_NAME` and
+/// `GIT_AUTHOR_EMAIL` variables or from the git config.
+fn git_author() -> Option<String> {
+    let git_config = |key: &str| {
+        let output = Command::new("git").args(["config", key]).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let non_empty = |value: String| Some(value).filter(|value| !value.is_empty());
+
+    let name = std::env::var("GIT_AUTHOR_NAME")
+        .ok()
+        .and_then(non_empty)
+        .or_else(|| git_config("user.name").and_then(non_empty))?;
+    let email = std::env::var("GIT_AUTHOR_EMAIL")
+        .ok()
+        .and_then(non_empty)
+        .or_else(|| git_config("user.email").and_then(non_empty));
+    Some(match email {
+        Some(email) => format!("{} <{}>", name, email),
+        None => name,
+    })
+}
+
+/// Get the NACP author from a package author, without the email of a `Name <email>` author, since
+/// it's shown on the console.
+fn nacp_author(author: &str) -> &str {
+    match author.split('<').next().unwrap().trim() {
+        "" => author,
+        name => name,
+    }
+}
+
+/// Initialize a git repository in the project directory with an initial commit, adding `remote`
+/// as `origin` if specified.
+///
+/// Failures (like git not being installed) are reported as warnings, since the project itself was
+/// already created.
+fn setup_git_repository(path: &Path, remote: Option<&str>) {
+    if !run_git(path, &["init", "--quiet"]) {
+        return;
+    }
+    if let Some(remote) = remote {
+        if !run_git(path, &["remote", "add", "origin", remote]) {
+            return;
+        }
+    }
+
+    if run_git(path, &["add", "--all"]) {
+        run_git(path, &["commit", "--quiet", "-m", "Initial commit"]);
+    }
+}
+
+/// Run a git command in the specified directory, warning if it fails.
+fn run_git(path: &Path, args: &[&str]) -> bool {
+    match Command::new("git").args(args).current_dir(path).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            eprintln!("Warning: `git {}` failed ({})", args.join(" "), status);
+            false
+        }
+        Err(err) => {
+            eprintln!("Warning: failed to run `git {}`: {}", args.join(" "), err);
+            false
+        }
+    }
+}
+
+/// The `nx` dependency specification selected with `--nx-version`.
+#[derive(Debug, Clone)]
+pub enum NxVersion {
+    /// A crate version requirement.
+    Version(semver::VersionReq),
+    /// A branch of the git repository.
+    Branch(String),
+    /// A tag of the git repository.
+    Tag(String),
+    /// A revision of the git repository.
+    Rev(String),
+}
+
+impl NxVersion {
+    /// The dependency source fields to use in the generated manifest.
+    fn dependency_source(&self) -> String {
+        match self {
+            NxVersion::Version(req) => format!("version = \"{}\"", req),
+            NxVersion::Branch(branch) => {
+                format!("{}, branch = \"{}\"", NX_DEPENDENCY_SOURCE, branch)
+            }
+            NxVersion::Tag(tag) => format!("{}, tag = \"{}\"", NX_DEPENDENCY_SOURCE, tag),
+            NxVersion::Rev(rev) => format!("{}, rev = \"{}\"", NX_DEPENDENCY_SOURCE, rev),
+        }
+    }
+}
+
+fn parse_program_id(value: &str) -> Result<u64, String> {
+    build::parse_program_id(value).ok_or_else(|| format!("invalid hex program ID: `{}`", value))
+}
+
+fn parse_nx_version(value: &str) -> Result<NxVersion, String> {
+    let git_ref = |git_ref: &str| {
+        if git_ref.is_empty() || git_ref.contains(['"', '\\']) {
+            Err(format!("invalid git reference: `{}`", git_ref))
+        } else {
+            Ok(git_ref.to_string())
+        }
+    };
+
+    if let Some(branch) = value.strip_prefix("branch:") {
+        git_ref(branch).map(NxVersion::Branch)
+    } else if let Some(tag) = value.strip_prefix("tag:") {
+        git_ref(tag).map(NxVersion::Tag)
+    } else if let Some(rev) = value.strip_prefix("rev:") {
+        git_ref(rev).map(NxVersion::Rev)
+    } else {
+        value
+            .parse::<semver::VersionReq>()
+            .map(NxVersion::Version)
+            .map_err(|err| format!("invalid version requirement: {}", err))
+    }
 }
 
 #[derive(Debug, Default)]
 struct PackageInfo<'a> {
     name: &'a str,
     author: &'a str,
+    nacp_author: &'a str,
     version: &'a str,
     edition: u16,
     program_id: u64,
 }
 
 fn process_default_file(file: &str, replace_info: &PackageInfo<'_>) -> String {
+    // The authors are substituted in TOML strings
+    let toml_escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
     file.replace("<name>", replace_info.name)
-        .replace("<author>", replace_info.author)
+        .replace("<author>", &toml_escape(replace_info.author))
+        .replace("<nacp_author>", &toml_escape(replace_info.nacp_author))
         .replace("<version>", replace_info.version)
         .replace("<edition>", format!("{}", replace_info.edition).as_str())
         .replace(
@@ -138,12 +450,46 @@ fn process_default_file(file: &str, replace_info: &PackageInfo<'_>) -> String {
         )
 }
 
+/// The version control system to initialize in new projects.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum Vcs {
+    /// A git repository.
+    Git,
+    /// No repository.
+    None,
+}
+
+/// The framework whose skeleton is used for new overlay packages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[value(rename_all = "lower")]
+pub enum OverlayFramework {
+    /// A minimal overlay entrypoint, only using `nx`.
+    Raw,
+    /// The libtesla overlay framework.
+    Libtesla,
+}
+
+impl fmt::Display for OverlayFramework {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fmt_str = match self {
+            OverlayFramework::Raw => "raw",
+            OverlayFramework::Libtesla => "libtesla",
+        };
+
+        write!(f, "{}", fmt_str)
+    }
+}
+
 #[derive(Debug, Copy, Clone, clap::ValueEnum)]
 #[value(rename_all = "lower")]
 pub enum PackageKind {
     Lib,
     Nro,
     Nsp,
+    #[value(alias = "overlay")]
+    Ovl,
+    Kip,
 }
 
 impl fmt::Display for PackageKind {
@@ -152,8 +498,61 @@ impl fmt::Display for PackageKind {
             PackageKind::Lib => "lib",
             PackageKind::Nro => "nro",
             PackageKind::Nsp => "nsp",
+            PackageKind::Ovl => "ovl",
+            PackageKind::Kip => "kip",
         };
 
         write!(f, "{}", fmt_str)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_id_substitution() {
+        let info = PackageInfo {
+            name: "test",
+            author: "Author",
+            nacp_author: "Author",
+            version: INITIAL_VERSION,
+            edition: 2021,
+            program_id: 0x0100123456789ABC,
+        };
+        for (kind, cargo_toml) in [
+            (PackageKind::Nro, DEFAULT_NRO_CARGO_TOML),
+            (PackageKind::Nsp, DEFAULT_NSP_CARGO_TOML),
+            (PackageKind::Ovl, DEFAULT_OVL_CARGO_TOML),
+            (PackageKind::Kip, DEFAULT_KIP_CARGO_TOML),
+        ] {
+            let cargo_toml = process_default_file(cargo_toml, &info);
+            assert!(
+                cargo_toml.contains("\"0x0100123456789ABC\""),
+                "no program ID in the {} manifest",
+                kind
+            );
+            assert!(!cargo_toml.contains("0x0100AAAABBBBCCCC"), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn nacp_author_substitution() {
+        assert_eq!(nacp_author("Name <name@example.com>"), "Name");
+        assert_eq!(nacp_author("Name"), "Name");
+
+        let info = PackageInfo {
+            name: "test",
+            author: "Name <name@example.com>",
+            nacp_author: nacp_author("Name <name@example.com>"),
+            version: INITIAL_VERSION,
+            edition: 2021,
+            program_id: DEFAULT_PROGRAM_ID,
+        };
+        for cargo_toml in [DEFAULT_NRO_CARGO_TOML, DEFAULT_OVL_CARGO_TOML] {
+            let cargo_toml = process_default_file(cargo_toml, &info);
+            assert!(cargo_toml.contains("authors = [\"Name <name@example.com>\"]"));
+            assert!(cargo_toml.contains("default_author = \"Name\""));
+        }
+    }
+}
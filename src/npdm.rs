@@ -0,0 +1,364 @@
+//! Read-only parsing of binary NPDM files.
+//!
+//! Only the fields useful to inspect an NPDM are parsed: the META header, the program ID and the
+//! service access and kernel capabilities of the ACI0 section.
+//!
+//! See: https://switchbrew.org/wiki/NPDM
+
+use std::{fmt, io};
+
+use crate::{
+    bytes::{invalid_data, read_u32, read_u64, slice},
+    log_file::log_println,
+};
+
+/// The META header magic.
+const META_MAGIC: &[u8] = b"META";
+
+/// The ACI0 section magic.
+const ACI0_MAGIC: &[u8] = b"ACI0";
+
+/// The kernel capability descriptor used as padding.
+const KERNEL_CAPABILITY_EMPTY: u32 = 0xFFFF_FFFF;
+
+/// The number of system calls each `EnableSystemCalls` descriptor covers.
+const SYSCALLS_PER_DESCRIPTOR: u32 = 24;
+
+/// A parsed NPDM file.
+pub struct NpdmFile {
+    /// The program name.
+    pub name: String,
+    /// The program ID.
+    pub program_id: u64,
+    /// The main thread priority.
+    pub main_thread_priority: u8,
+    /// The main thread CPU core.
+    pub main_thread_core_number: u8,
+    /// The main thread stack size.
+    pub main_thread_stack_size: u32,
+    /// Whether the program is 64-bit.
+    pub is_64_bit: bool,
+    /// The services the program can access, with whether it can also host them.
+    pub services: Vec<(String, bool)>,
+    /// The kernel capabilities of the program.
+    pub kernel_capabilities: Vec<KernelCapability>,
+}
+
+/// A kernel capability of an NPDM.
+#[derive(Debug, PartialEq)]
+pub enum KernelCapability {
+    /// The allowed thread priorities and CPU cores.
+    ThreadInfo {
+        lowest_priority: u32,
+        highest_priority: u32,
+        min_core_number: u32,
+        max_core_number: u32,
+    },
+    /// The allowed system calls.
+    EnableSystemCalls(Vec<u32>),
+    /// The allowed interrupts.
+    EnableInterrupts(Vec<u32>),
+    /// The program type.
+    MiscParams { program_type: u32 },
+    /// The minimum kernel version.
+    KernelVersion { major: u32, minor: u32 },
+    /// The maximum handle table size.
+    HandleTableSize(u32),
+    /// The debugging flags.
+    MiscFlags {
+        enable_debug: bool,
+        force_debug: bool,
+    },
+    /// Any other capability (like memory mappings), left undecoded.
+    Other(u32),
+}
+
+impl NpdmFile {
+    /// Parse the NPDM file contents.
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if slice(data, 0, META_MAGIC.len())? != META_MAGIC {
+            return Err(invalid_data("invalid NPDM magic"));
+        }
+
+        let aci_offset = read_u32(data, 0x70)? as usize;
+        let aci_size = read_u32(data, 0x74)? as usize;
+        let aci = slice(data, aci_offset, aci_size)?;
+        if slice(aci, 0, ACI0_MAGIC.len())? != ACI0_MAGIC {
+            return Err(invalid_data("invalid ACI0 magic"));
+        }
+
+        let srv_access = slice(
+            aci,
+            read_u32(aci, 0x28)? as usize,
+            read_u32(aci, 0x2C)? as usize,
+        )?;
+        let kernel_capabilities = slice(
+            aci,
+            read_u32(aci, 0x30)? as usize,
+            read_u32(aci, 0x34)? as usize,
+        )?;
+
+        Ok(Self {
+            name: read_string(slice(data, 0x20, 0x10)?),
+            program_id: read_u64(aci, 0x10)?,
+            main_thread_priority: data[0xE],
+            main_thread_core_number: data[0xF],
+            main_thread_stack_size: read_u32(data, 0x1C)?,
+            is_64_bit: data[0xC] & 1 != 0,
+            services: parse_services(srv_access)?,
+            kernel_capabilities: parse_kernel_capabilities(kernel_capabilities)?,
+        })
+    }
+}
+
+impl fmt::Display for KernelCapability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelCapability::ThreadInfo {
+                lowest_priority,
+                highest_priority,
+                min_core_number,
+                max_core_number,
+            } => write!(
+                f,
+                "Thread info: priorities {}-{}, cores {}-{}",
+                highest_priority, lowest_priority, min_core_number, max_core_number
+            ),
+            KernelCapability::EnableSystemCalls(syscalls) => {
+                let syscalls: Vec<_> = syscalls.iter().map(|id| format!("{:#04X}", id)).collect();
+                write!(f, "System calls: {}", syscalls.join(", "))
+            }
+            KernelCapability::EnableInterrupts(interrupts) => {
+                let interrupts: Vec<_> = interrupts.iter().map(u32::to_string).collect();
+                write!(f, "Interrupts: {}", interrupts.join(", "))
+            }
+            KernelCapability::MiscParams { program_type } => {
+                write!(f, "Program type: {}", program_type)
+            }
+            KernelCapability::KernelVersion { major, minor } => {
+                write!(f, "Kernel version: {}.{}", major, minor)
+            }
+            KernelCapability::HandleTableSize(size) => write!(f, "Handle table size: {}", size),
+            KernelCapability::MiscFlags {
+                enable_debug,
+                force_debug,
+            } => write!(
+                f,
+                "Debug flags: enable debug {}, force debug {}",
+                enable_debug, force_debug
+            ),
+            KernelCapability::Other(descriptor) => write!(f, "Other: {:#010X}", descriptor),
+        }
+    }
+}
+
+/// Print a summary of a parsed NPDM.
+pub fn print_summary(npdm: &NpdmFile) {
+    log_println!("Name: {}", npdm.name);
+    log_println!("Program ID: {:#018X}", npdm.program_id);
+    log_println!("64-bit: {}", npdm.is_64_bit);
+    log_println!(
+        "Main thread: priority {}, core {}, stack size {:#X}",
+        npdm.main_thread_priority,
+        npdm.main_thread_core_number,
+        npdm.main_thread_stack_size
+    );
+
+    log_println!("Services:");
+    for (name, is_host) in &npdm.services {
+        log_println!("  {}{}", name, if *is_host { " (host)" } else { "" });
+    }
+
+    log_println!("Kernel capabilities:");
+    for capability in &npdm.kernel_capabilities {
+        log_println!("  {}", capability);
+    }
+}
+
+/// Print a hex dump of `data`, 16 bytes per line.
+pub fn print_hex_dump(data: &[u8]) {
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let bytes: Vec<_> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                0x20..=0x7E => byte as char,
+                _ => '.',
+            })
+            .collect();
+        log_println!("{:08X}  {:<47}  {}", line * 16, bytes.join(" "), text);
+    }
+}
+
+/// Parse the service access control entries.
+fn parse_services(mut data: &[u8]) -> io::Result<Vec<(String, bool)>> {
+    let mut services = Vec::new();
+    while let Some((&control, rest)) = data.split_first() {
+        if control == 0 {
+            break;
+        }
+
+        let len = (control & 0x7) as usize + 1;
+        let name = slice(rest, 0, len)?;
+        services.push((read_string(name), control & 0x80 != 0));
+        data = &rest[len..];
+    }
+
+    Ok(services)
+}
+
+/// Parse the kernel capability descriptors.
+pub fn parse_kernel_capabilities(data: &[u8]) -> io::Result<Vec<KernelCapability>> {
+    let mut capabilities: Vec<KernelCapability> = Vec::new();
+    for offset in (0..data.len() / 4).map(|index| index * 4) {
+        let descriptor = read_u32(data, offset)?;
+        if descriptor == KERNEL_CAPABILITY_EMPTY {
+            continue;
+        }
+
+        // The descriptor type is given by the number of trailing set bits
+        let capability = match descriptor.trailing_ones() {
+            3 => KernelCapability::ThreadInfo {
+                lowest_priority: bits(descriptor, 4, 6),
+                highest_priority: bits(descriptor, 10, 6),
+                min_core_number: bits(descriptor, 16, 8),
+                max_core_number: bits(descriptor, 24, 8),
+            },
+            4 => {
+                let base = bits(descriptor, 29, 3) * SYSCALLS_PER_DESCRIPTOR;
+                let syscalls = (0..SYSCALLS_PER_DESCRIPTOR)
+                    .filter(|bit| descriptor & (1 << (5 + bit)) != 0)
+                    .map(|bit| base + bit);
+
+                // Merge consecutive system call descriptors into a single capability
+                if let Some(KernelCapability::EnableSystemCalls(enabled)) = capabilities.last_mut()
+                {
+                    enabled.extend(syscalls);
+                    continue;
+                }
+                KernelCapability::EnableSystemCalls(syscalls.collect())
+            }
+            11 => KernelCapability::EnableInterrupts(
+                [bits(descriptor, 12, 10), bits(descriptor, 22, 10)]
+                    .into_iter()
+                    .filter(|&interrupt| interrupt != 0x3FF)
+                    .collect(),
+            ),
+            13 => KernelCapability::MiscParams {
+                program_type: bits(descriptor, 14, 3),
+            },
+            14 => KernelCapability::KernelVersion {
+                major: bits(descriptor, 19, 13),
+                minor: bits(descriptor, 15, 4),
+            },
+            15 => KernelCapability::HandleTableSize(bits(descriptor, 16, 10)),
+            16 => KernelCapability::MiscFlags {
+                enable_debug: descriptor & (1 << 17) != 0,
+                force_debug: descriptor & (1 << 18) != 0,
+            },
+            _ => KernelCapability::Other(descriptor),
+        };
+        capabilities.push(capability);
+    }
+
+    Ok(capabilities)
+}
+
+/// Extract `len` bits of `value` starting at bit `offset`.
+fn bits(value: u32, offset: u32, len: u32) -> u32 {
+    (value >> offset) & ((1 << len) - 1)
+}
+
+/// Read a NUL-padded string.
+fn read_string(data: &[u8]) -> String {
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal NPDM (without ACID), with a few services and kernel capabilities.
+    const TEST_NPDM: &[u8] = include_bytes!("../tests/fixtures/npdm/main.npdm");
+
+    #[test]
+    fn npdm_parse() {
+        let npdm = NpdmFile::parse(TEST_NPDM).unwrap();
+        assert_eq!(npdm.name, "fixture");
+        assert_eq!(npdm.program_id, 0x0100000000001234);
+        assert_eq!(npdm.main_thread_priority, 44);
+        assert_eq!(npdm.main_thread_core_number, 0);
+        assert_eq!(npdm.main_thread_stack_size, 0x100000);
+        assert!(npdm.is_64_bit);
+        assert_eq!(
+            npdm.services,
+            [
+                ("fsp-srv".to_string(), false),
+                ("sm:".to_string(), false),
+                ("fixture".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            npdm.kernel_capabilities,
+            [
+                KernelCapability::ThreadInfo {
+                    lowest_priority: 63,
+                    highest_priority: 24,
+                    min_core_number: 0,
+                    max_core_number: 3,
+                },
+                KernelCapability::EnableSystemCalls(vec![0x01, 0x21]),
+                KernelCapability::HandleTableSize(512),
+            ]
+        );
+        assert_eq!(
+            npdm.kernel_capabilities[0].to_string(),
+            "Thread info: priorities 24-63, cores 0-3"
+        );
+        assert_eq!(
+            npdm.kernel_capabilities[1].to_string(),
+            "System calls: 0x01, 0x21"
+        );
+    }
+
+    #[test]
+    fn npdm_invalid() {
+        assert!(NpdmFile::parse(b"NSO0").is_err());
+
+        let mut npdm = TEST_NPDM.to_vec();
+        npdm[0x80] = b'X';
+        assert!(NpdmFile::parse(&npdm).is_err());
+
+        // The ACI0 section is past the end of the file
+        assert!(NpdmFile::parse(&TEST_NPDM[..0xC0]).is_err());
+    }
+
+    #[test]
+    fn kernel_capability_types() {
+        let descriptors: Vec<u8> = [
+            0x0080_17FFu32,
+            0x0000_5FFF,
+            0x0018_3FFF,
+            0x0006_FFFF,
+            KERNEL_CAPABILITY_EMPTY,
+            0x0000_003F,
+        ]
+        .iter()
+        .flat_map(|descriptor| descriptor.to_le_bytes())
+        .collect();
+        assert_eq!(
+            parse_kernel_capabilities(&descriptors).unwrap(),
+            [
+                KernelCapability::EnableInterrupts(vec![1, 2]),
+                KernelCapability::MiscParams { program_type: 1 },
+                KernelCapability::KernelVersion { major: 3, minor: 0 },
+                KernelCapability::MiscFlags {
+                    enable_debug: true,
+                    force_debug: true,
+                },
+                KernelCapability::Other(0x0000_003F),
+            ]
+        );
+    }
+}
@@ -0,0 +1,597 @@
+//! Parsing of existing NRO files, and writing of NROs from already loaded modules.
+//!
+//! Only the parts needed to inspect or convert an NRO are parsed: the header, the segments and the
+//! trailing asset section (icon, NACP and RomFs).
+//!
+//! See: https://switchbrew.org/wiki/NRO
+
+use std::{collections::HashSet, io, path::Path};
+
+use crate::{
+    bytes::{invalid_data, read_u32, read_u64, slice, write_u32, write_u64},
+    nso::{Module, Segment},
+};
+
+/// The NRO header magic, located at offset `0x10`.
+const NRO_MAGIC: &[u8] = b"NRO0";
+
+/// The offset of the NRO header.
+const NRO_HEADER_OFFSET: usize = 0x10;
+
+/// The size of the NRO start and header, which overlap the beginning of the `.text` segment.
+const NRO_HEADER_END: usize = 0x80;
+
+/// The asset section header magic.
+const ASSET_MAGIC: &[u8] = b"ASET";
+
+/// The asset section header size.
+const ASSET_HEADER_SIZE: usize = 0x38;
+
+/// The RomFs header size.
+const ROMFS_HEADER_SIZE: u64 = 0x50;
+
+/// The RomFs metadata tables' empty entry marker.
+const ROMFS_ENTRY_EMPTY: u32 = 0xFFFF_FFFF;
+
+/// The dimensions of the NRO icon expected by the home menu.
+pub const ICON_DIMENSIONS: (u16, u16) = (256, 256);
+
+/// An NRO file loaded in memory.
+pub struct NroFile {
+    data: Vec<u8>,
+    asset_offset: Option<usize>,
+}
+
+impl NroFile {
+    /// Read and parse the NRO file at `path`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    /// Parse the NRO file contents.
+    pub fn parse(data: Vec<u8>) -> io::Result<Self> {
+        if slice(&data, NRO_HEADER_OFFSET, NRO_MAGIC.len())? != NRO_MAGIC {
+            return Err(invalid_data("invalid NRO magic"));
+        }
+
+        // The asset section (if any) is appended right after the NRO itself
+        let size = read_u32(&data, NRO_HEADER_OFFSET + 0x8)? as usize;
+        let asset_offset = match data.get(size..size + ASSET_MAGIC.len()) {
+            Some(magic) if magic == ASSET_MAGIC => Some(size),
+            _ => None,
+        };
+
+        Ok(Self { data, asset_offset })
+    }
+
+    /// The segments of the module.
+    pub fn module(&self) -> io::Result<Module> {
+        let header = NRO_HEADER_OFFSET;
+        let mut segments = Vec::with_capacity(3);
+        for index in 0..3 {
+            let offset = read_u32(&self.data, header + 0x10 + index * 0x8)?;
+            let size = read_u32(&self.data, header + 0x14 + index * 0x8)? as usize;
+            segments.push(Segment {
+                memory_offset: offset,
+                data: slice(&self.data, offset as usize, size)?.to_vec(),
+            });
+        }
+
+        let mut rodata_tables = [(0, 0); 3];
+        for (index, table) in rodata_tables.iter_mut().enumerate() {
+            *table = (
+                read_u32(&self.data, header + 0x58 + index * 0x8)?,
+                read_u32(&self.data, header + 0x5C + index * 0x8)?,
+            );
+        }
+
+        Ok(Module {
+            segments: segments.try_into().ok().unwrap(),
+            bss_size: read_u32(&self.data, header + 0x28)?,
+            module_id: slice(&self.data, header + 0x30, 0x20)?.try_into().unwrap(),
+            rodata_tables,
+        })
+    }
+
+    /// The embedded icon (JPEG), if any.
+    pub fn icon(&self) -> io::Result<Option<&[u8]>> {
+        self.asset(0)
+    }
+
+    /// The embedded NACP, if any.
+    pub fn nacp(&self) -> io::Result<Option<&[u8]>> {
+        self.asset(1)
+    }
+
+    /// The embedded RomFs image, if any.
+    pub fn romfs(&self) -> io::Result<Option<&[u8]>> {
+        self.asset(2)
+    }
+
+    /// Get the asset section entry at `index` (icon, NACP and RomFs, in that order).
+    fn asset(&self, index: usize) -> io::Result<Option<&[u8]>> {
+        let Some(asset_offset) = self.asset_offset else {
+            return Ok(None);
+        };
+
+        let entry_offset = asset_offset + 0x8 + index * 0x10;
+        let offset = read_u64(&self.data, entry_offset)? as usize;
+        let size = read_u64(&self.data, entry_offset + 0x8)? as usize;
+        if size == 0 {
+            return Ok(None);
+        }
+
+        slice(&self.data, asset_offset + offset, size).map(Some)
+    }
+}
+
+/// Write `module` as an NRO, embedding the `icon` (JPEG) and `nacp` in its asset section.
+///
+/// The segments are placed at their memory offsets, and the NRO header replaces the area the
+/// `.text` segment reserves for it (after the NRO start, which is kept as is).
+pub fn write_nro(module: &Module, icon: &[u8], nacp: &[u8]) -> io::Result<Vec<u8>> {
+    let [text, rodata, data] = &module.segments;
+    if text.memory_offset != 0 || text.data.len() < NRO_HEADER_END {
+        return Err(invalid_data(
+            "the .text segment has no room for the NRO header",
+        ));
+    }
+
+    let size = data.memory_offset as usize + data.data.len();
+    let mut nro = vec![0u8; size];
+    for segment in &module.segments {
+        let offset = segment.memory_offset as usize;
+        slice(&nro, offset, segment.data.len())?;
+        nro[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+    }
+
+    let header = NRO_HEADER_OFFSET;
+    nro[header..NRO_HEADER_END].fill(0);
+    nro[header..header + NRO_MAGIC.len()].copy_from_slice(NRO_MAGIC);
+    write_u32(&mut nro, header + 0x8, size as u32);
+    for (index, segment) in [text, rodata, data].into_iter().enumerate() {
+        write_u32(&mut nro, header + 0x10 + index * 0x8, segment.memory_offset);
+        write_u32(
+            &mut nro,
+            header + 0x14 + index * 0x8,
+            segment.data.len() as u32,
+        );
+    }
+    write_u32(&mut nro, header + 0x28, module.bss_size);
+    nro[header + 0x30..header + 0x50].copy_from_slice(&module.module_id);
+    for (index, (offset, size)) in module.rodata_tables.iter().enumerate() {
+        write_u32(&mut nro, header + 0x58 + index * 0x8, *offset);
+        write_u32(&mut nro, header + 0x5C + index * 0x8, *size);
+    }
+
+    // The asset section offsets are relative to its header
+    let mut asset_header = vec![0u8; ASSET_HEADER_SIZE];
+    asset_header[..ASSET_MAGIC.len()].copy_from_slice(ASSET_MAGIC);
+    write_u64(&mut asset_header, 0x8, ASSET_HEADER_SIZE as u64);
+    write_u64(&mut asset_header, 0x10, icon.len() as u64);
+    write_u64(
+        &mut asset_header,
+        0x18,
+        (ASSET_HEADER_SIZE + icon.len()) as u64,
+    );
+    write_u64(&mut asset_header, 0x20, nacp.len() as u64);
+    nro.extend_from_slice(&asset_header);
+    nro.extend_from_slice(icon);
+    nro.extend_from_slice(nacp);
+
+    Ok(nro)
+}
+
+/// Extract all the files of a RomFs image into `out_dir`.
+///
+/// Returns the number of extracted files.
+pub fn extract_romfs(romfs: &[u8], out_dir: &Path) -> io::Result<usize> {
+    let header_size = read_u64(romfs, 0x0)?;
+    if header_size != ROMFS_HEADER_SIZE {
+        return Err(invalid_data("invalid RomFs header size"));
+    }
+
+    let tables = RomFsTables {
+        dir_meta: slice(
+            romfs,
+            read_u64(romfs, 0x18)? as usize,
+            read_u64(romfs, 0x20)? as usize,
+        )?,
+        file_meta: slice(
+            romfs,
+            read_u64(romfs, 0x38)? as usize,
+            read_u64(romfs, 0x40)? as usize,
+        )?,
+        file_data: romfs
+            .get(read_u64(romfs, 0x48)? as usize..)
+            .ok_or_else(|| invalid_data("invalid RomFs file data offset"))?,
+    };
+
+    std::fs::create_dir_all(out_dir)?;
+    tables.extract_dir(0, out_dir, &mut VisitedEntries::default())
+}
+
+/// The offsets of the RomFs entries already extracted.
+#[derive(Default)]
+struct VisitedEntries {
+    dirs: HashSet<u32>,
+    files: HashSet<u32>,
+}
+
+/// The RomFs tables needed to walk and extract its contents.
+struct RomFsTables<'a> {
+    dir_meta: &'a [u8],
+    file_meta: &'a [u8],
+    file_data: &'a [u8],
+}
+
+impl RomFsTables<'_> {
+    /// Extract the contents of the directory entry at `dir_offset` into `path`.
+    ///
+    /// The offsets of the entries already extracted are kept in `visited`, so a malformed RomFs
+    /// whose entries loop back fails instead of recursing forever.
+    fn extract_dir(
+        &self,
+        dir_offset: u32,
+        path: &Path,
+        visited: &mut VisitedEntries,
+    ) -> io::Result<usize> {
+        if !visited.dirs.insert(dir_offset) {
+            return Err(invalid_data("the RomFs directory entries contain a cycle"));
+        }
+        let dir = dir_offset as usize;
+        let mut count = 0;
+
+        // Extract the files of this directory
+        let mut file_offset = read_u32(self.dir_meta, dir + 0xC)?;
+        while file_offset != ROMFS_ENTRY_EMPTY {
+            if !visited.files.insert(file_offset) {
+                return Err(invalid_data("the RomFs file entries contain a cycle"));
+            }
+            let file = file_offset as usize;
+            let data_offset = read_u64(self.file_meta, file + 0x8)? as usize;
+            let data_size = read_u64(self.file_meta, file + 0x10)? as usize;
+            let name = read_entry_name(self.file_meta, file + 0x1C, file + 0x20)?;
+
+            let file_path = path.join(name);
+            check_not_symlink(&file_path)?;
+            std::fs::write(&file_path, slice(self.file_data, data_offset, data_size)?)?;
+            count += 1;
+
+            file_offset = read_u32(self.file_meta, file + 0x4)?;
+        }
+
+        // Recurse into the child directories
+        let mut child_offset = read_u32(self.dir_meta, dir + 0x8)?;
+        while child_offset != ROMFS_ENTRY_EMPTY {
+            let child = child_offset as usize;
+            let name = read_entry_name(self.dir_meta, child + 0x14, child + 0x18)?;
+
+            let child_path = path.join(name);
+            check_not_symlink(&child_path)?;
+            std::fs::create_dir_all(&child_path)?;
+            count += self.extract_dir(child_offset, &child_path, visited)?;
+
+            child_offset = read_u32(self.dir_meta, child + 0x4)?;
+        }
+
+        Ok(count)
+    }
+}
+
+/// Fail if `path` is an existing symlink, which the extracted files would be written through (and
+/// possibly outside of the output directory).
+fn check_not_symlink(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "refusing to extract through the symlink {}",
+                path.to_string_lossy()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Read a RomFs entry name, rejecting names that would escape the output directory.
+fn read_entry_name(table: &[u8], len_offset: usize, name_offset: usize) -> io::Result<&str> {
+    let name_len = read_u32(table, len_offset)? as usize;
+    let name = std::str::from_utf8(slice(table, name_offset, name_len)?)
+        .map_err(|_| invalid_data("invalid RomFs entry name"))?;
+
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(invalid_data("invalid RomFs entry name"));
+    }
+
+    Ok(name)
+}
+
+/// Check that an icon is a JPEG with the dimensions the home menu expects.
+pub fn check_icon(icon: &[u8]) -> io::Result<()> {
+    let (width, height) = jpeg_dimensions(icon)?;
+    if (width, height) != ICON_DIMENSIONS {
+        return Err(invalid_data(&format!(
+            "the icon is {}x{}, but it must be {}x{}",
+            width, height, ICON_DIMENSIONS.0, ICON_DIMENSIONS.1
+        )));
+    }
+    Ok(())
+}
+
+/// Get the dimensions (width and height) of a JPEG from its frame header.
+pub fn jpeg_dimensions(data: &[u8]) -> io::Result<(u16, u16)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        let format = if data.starts_with(b"\x89PNG") {
+            "a PNG"
+        } else if data.starts_with(b"GIF8") {
+            "a GIF"
+        } else if data.starts_with(b"BM") {
+            "a BMP"
+        } else {
+            "not a JPEG"
+        };
+        return Err(invalid_data(&format!(
+            "the icon is {}, but it must be a JPEG",
+            format
+        )));
+    }
+
+    let read_u16_be = |offset: usize| -> io::Result<u16> {
+        Ok(u16::from_be_bytes(
+            slice(data, offset, 2)?.try_into().unwrap(),
+        ))
+    };
+    let mut pos = 2;
+    loop {
+        if slice(data, pos, 1)? != [0xFF] {
+            return Err(invalid_data("invalid JPEG marker"));
+        }
+        let marker = slice(data, pos + 1, 1)?[0];
+        match marker {
+            // Fill bytes before a marker
+            0xFF => pos += 1,
+            // Markers without a segment
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            // The start of frame markers, except for DHT (0xC4), JPG (0xC8) and DAC (0xCC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                return Ok((read_u16_be(pos + 7)?, read_u16_be(pos + 5)?));
+            }
+            0xD9 | 0xDA => return Err(invalid_data("the JPEG has no frame header")),
+            _ => pos += 2 + read_u16_be(pos + 2)? as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_module() -> Module {
+        Module {
+            segments: [
+                Segment {
+                    memory_offset: 0,
+                    data: (0..0x1000).map(|i| (i % 251) as u8).collect(),
+                },
+                Segment {
+                    memory_offset: 0x1000,
+                    data: vec![0x11; 0x800],
+                },
+                Segment {
+                    memory_offset: 0x2000,
+                    data: vec![0x22; 0x400],
+                },
+            ],
+            bss_size: 0x1000,
+            module_id: [0x42; 0x20],
+            rodata_tables: [(0x100, 0x10), (0x200, 0x20), (0x300, 0x30)],
+        }
+    }
+
+    #[test]
+    fn nro_round_trip() {
+        let module = test_module();
+        let nro = write_nro(&module, b"icon", b"nacp").unwrap();
+        let nro = NroFile::parse(nro).unwrap();
+
+        let parsed = nro.module().unwrap();
+        for (segment, expected) in parsed.segments.iter().zip(&module.segments) {
+            assert_eq!(segment.memory_offset, expected.memory_offset);
+            assert_eq!(segment.data.len(), expected.data.len());
+        }
+        // The NRO header replaces the start of the `.text` segment, after the NRO start
+        let [text, rodata, data] = &parsed.segments;
+        assert_eq!(
+            text.data[..NRO_HEADER_OFFSET],
+            module.segments[0].data[..NRO_HEADER_OFFSET]
+        );
+        assert_eq!(
+            &text.data[NRO_HEADER_OFFSET..NRO_HEADER_OFFSET + 4],
+            NRO_MAGIC
+        );
+        assert_eq!(
+            text.data[NRO_HEADER_END..],
+            module.segments[0].data[NRO_HEADER_END..]
+        );
+        assert_eq!(rodata.data, module.segments[1].data);
+        assert_eq!(data.data, module.segments[2].data);
+        assert_eq!(parsed.bss_size, module.bss_size);
+        assert_eq!(parsed.module_id, module.module_id);
+        assert_eq!(parsed.rodata_tables, module.rodata_tables);
+
+        assert_eq!(nro.icon().unwrap(), Some(&b"icon"[..]));
+        assert_eq!(nro.nacp().unwrap(), Some(&b"nacp"[..]));
+        assert_eq!(nro.romfs().unwrap(), None);
+    }
+
+    #[test]
+    fn nro_without_assets() {
+        let mut nro = write_nro(&test_module(), b"icon", b"nacp").unwrap();
+        nro.truncate(0x2400);
+        let nro = NroFile::parse(nro).unwrap();
+        assert_eq!(nro.icon().unwrap(), None);
+        assert!(nro.module().is_ok());
+    }
+
+    #[test]
+    fn nro_invalid() {
+        assert!(NroFile::parse(vec![0; 0x20]).is_err());
+        assert!(NroFile::parse(vec![0; 4]).is_err());
+
+        // The `.text` segment must start the module and hold the NRO header
+        let mut module = test_module();
+        module.segments[0].data.truncate(NRO_HEADER_END - 1);
+        assert!(write_nro(&module, b"", b"").is_err());
+    }
+
+    /// A JPEG header with an APP0 segment and a frame header of type `sof`.
+    fn test_jpeg(sof: u8, width: u16, height: u16) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+        jpeg.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        // Fill bytes may precede a marker
+        jpeg.extend_from_slice(&[0xFF, 0xFF, sof, 0x00, 0x11, 0x08]);
+        jpeg.extend_from_slice(&height.to_be_bytes());
+        jpeg.extend_from_slice(&width.to_be_bytes());
+        jpeg.extend_from_slice(&[0x03, 0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01]);
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn jpeg_frame_dimensions() {
+        // Baseline and progressive
+        assert_eq!(
+            jpeg_dimensions(&test_jpeg(0xC0, 256, 192)).unwrap(),
+            (256, 192)
+        );
+        assert_eq!(
+            jpeg_dimensions(&test_jpeg(0xC2, 640, 480)).unwrap(),
+            (640, 480)
+        );
+
+        // A DHT segment isn't a frame header
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xC4, 0x00, 0x03, 0x00];
+        jpeg.extend_from_slice(&test_jpeg(0xC0, 256, 256)[2..]);
+        assert_eq!(jpeg_dimensions(&jpeg).unwrap(), (256, 256));
+
+        assert_eq!(
+            jpeg_dimensions(crate::build::DEFAULT_NRO_ICON).unwrap(),
+            ICON_DIMENSIONS
+        );
+    }
+
+    #[test]
+    fn jpeg_invalid() {
+        let err = jpeg_dimensions(b"\x89PNG\r\n\x1a\n").unwrap_err();
+        assert_eq!(err.to_string(), "the icon is a PNG, but it must be a JPEG");
+
+        // Truncated in the APP0 segment and in the frame header
+        let jpeg = test_jpeg(0xC0, 256, 256);
+        assert!(jpeg_dimensions(&jpeg[..10]).is_err());
+        assert!(jpeg_dimensions(&jpeg[..26]).is_err());
+
+        // The scan starts without a frame header
+        assert!(jpeg_dimensions(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]).is_err());
+        assert!(jpeg_dimensions(&[0xFF, 0xD8, 0x00]).is_err());
+    }
+
+    #[test]
+    fn icon_dimensions() {
+        assert!(check_icon(crate::build::DEFAULT_NRO_ICON).is_ok());
+        let err = check_icon(&test_jpeg(0xC0, 128, 128)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the icon is 128x128, but it must be 256x256"
+        );
+    }
+
+    /// A RomFs with the `a.txt` file and the `sub` directory (the child of the root `sub_parent`
+    /// one), which holds the `b.txt` file.
+    fn test_romfs(sub_child: u32) -> Vec<u8> {
+        let dir_entry = |sibling: u32, child: u32, file: u32, name: &str| {
+            let mut entry = vec![0u8; 0x18];
+            write_u32(&mut entry, 0x4, sibling);
+            write_u32(&mut entry, 0x8, child);
+            write_u32(&mut entry, 0xC, file);
+            write_u32(&mut entry, 0x14, name.len() as u32);
+            entry.extend_from_slice(name.as_bytes());
+            entry.resize(entry.len().next_multiple_of(4), 0);
+            entry
+        };
+        let file_entry = |data_offset: u64, data_size: u64, name: &str| {
+            let mut entry = vec![0u8; 0x20];
+            write_u32(&mut entry, 0x4, ROMFS_ENTRY_EMPTY);
+            write_u64(&mut entry, 0x8, data_offset);
+            write_u64(&mut entry, 0x10, data_size);
+            write_u32(&mut entry, 0x1C, name.len() as u32);
+            entry.extend_from_slice(name.as_bytes());
+            entry.resize(entry.len().next_multiple_of(4), 0);
+            entry
+        };
+
+        let mut dir_meta = dir_entry(ROMFS_ENTRY_EMPTY, 0x18, 0, "");
+        dir_meta.extend(dir_entry(ROMFS_ENTRY_EMPTY, sub_child, 0x28, "sub"));
+        let mut file_meta = file_entry(0, 1, "a.txt");
+        file_meta.extend(file_entry(1, 1, "b.txt"));
+
+        let mut romfs = vec![0u8; ROMFS_HEADER_SIZE as usize];
+        let dir_meta_offset = ROMFS_HEADER_SIZE;
+        let file_meta_offset = dir_meta_offset + dir_meta.len() as u64;
+        let file_data_offset = file_meta_offset + file_meta.len() as u64;
+        write_u64(&mut romfs, 0x0, ROMFS_HEADER_SIZE);
+        write_u64(&mut romfs, 0x18, dir_meta_offset);
+        write_u64(&mut romfs, 0x20, dir_meta.len() as u64);
+        write_u64(&mut romfs, 0x38, file_meta_offset);
+        write_u64(&mut romfs, 0x40, file_meta.len() as u64);
+        write_u64(&mut romfs, 0x48, file_data_offset);
+        romfs.extend(dir_meta);
+        romfs.extend(file_meta);
+        romfs.extend_from_slice(b"ab");
+        romfs
+    }
+
+    /// Create an empty directory for a test in the system temporary directory.
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("cargo-nx-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn romfs_extract() {
+        let dir = test_dir("romfs-extract");
+        assert_eq!(
+            extract_romfs(&test_romfs(ROMFS_ENTRY_EMPTY), &dir).unwrap(),
+            2
+        );
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(dir.join("sub").join("b.txt")).unwrap(), b"b");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn romfs_cycle() {
+        // The `sub` directory is its own child
+        let dir = test_dir("romfs-cycle");
+        assert!(extract_romfs(&test_romfs(0x18), &dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn romfs_symlink() {
+        let dir = test_dir("romfs-symlink");
+        let outside = dir.join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let out_dir = dir.join("out");
+        std::fs::create_dir(&out_dir).unwrap();
+        std::os::unix::fs::symlink(&outside, out_dir.join("sub")).unwrap();
+
+        assert!(extract_romfs(&test_romfs(ROMFS_ENTRY_EMPTY), &out_dir).is_err());
+        assert!(!outside.join("b.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! Parsing and writing of NSO files.
+//!
+//! NSOs are written uncompressed (with the segment hashes checked by the loader), while
+//! LZ4-compressed segments are decompressed when parsing.
+//!
+//! See: https://switchbrew.org/wiki/NSO
+
+use std::io;
+
+use sha2::{Digest, Sha256};
+
+use crate::bytes::{invalid_data, read_u32, slice, write_u32};
+
+/// The NSO header magic.
+const NSO_MAGIC: &[u8] = b"NSO0";
+
+/// The NSO header size, after which the segments are stored.
+const NSO_HEADER_SIZE: usize = 0x100;
+
+/// The flag marking the `.text` segment as compressed (shifted by the segment index for the
+/// others).
+const FLAG_COMPRESSED: u32 = 1 << 0;
+
+/// The flags enabling the hash check of the three segments.
+const FLAGS_CHECK_HASH: u32 = 0b111 << 3;
+
+/// The loaded segments of an executable module, shared by NRO and NSO files.
+pub struct Module {
+    /// The `.text`, `.rodata` and `.data` segments, in that order.
+    pub segments: [Segment; 3],
+    /// The size of the `.bss` section, following the `.data` segment in memory.
+    pub bss_size: u32,
+    /// The module ID (build ID).
+    pub module_id: [u8; 0x20],
+    /// The offsets and sizes (relative to `.rodata`) of the embedded API info, `.dynstr` and
+    /// `.dynsym`, in that order.
+    pub rodata_tables: [(u32, u32); 3],
+}
+
+/// A segment of an executable module.
+pub struct Segment {
+    /// The offset of the segment in the module memory.
+    pub memory_offset: u32,
+    /// The segment (decompressed) contents.
+    pub data: Vec<u8>,
+}
+
+impl Module {
+    /// Parse the NSO file contents.
+    pub fn parse_nso(data: &[u8]) -> io::Result<Self> {
+        if slice(data, 0, NSO_MAGIC.len())? != NSO_MAGIC {
+            return Err(invalid_data("invalid NSO magic"));
+        }
+
+        let flags = read_u32(data, 0xC)?;
+        let mut segments = Vec::with_capacity(3);
+        for index in 0..3 {
+            let header = 0x10 + index * 0x10;
+            let file_offset = read_u32(data, header)? as usize;
+            let memory_offset = read_u32(data, header + 0x4)?;
+            let size = read_u32(data, header + 0x8)? as usize;
+            let file_size = read_u32(data, 0x60 + index * 0x4)? as usize;
+
+            let contents = slice(data, file_offset, file_size)?;
+            let contents = if flags & (FLAG_COMPRESSED << index) != 0 {
+                lz4_decompress(contents, size)?
+            } else {
+                contents.to_vec()
+            };
+            segments.push(Segment {
+                memory_offset,
+                data: contents,
+            });
+        }
+
+        let mut rodata_tables = [(0, 0); 3];
+        for (index, table) in rodata_tables.iter_mut().enumerate() {
+            *table = (
+                read_u32(data, 0x88 + index * 0x8)?,
+                read_u32(data, 0x8C + index * 0x8)?,
+            );
+        }
+
+        Ok(Self {
+            segments: segments.try_into().ok().unwrap(),
+            bss_size: read_u32(data, 0x3C)?,
+            module_id: slice(data, 0x40, 0x20)?.try_into().unwrap(),
+            rodata_tables,
+        })
+    }
+
+    /// Write the module as an uncompressed NSO.
+    pub fn write_nso(&self) -> Vec<u8> {
+        let mut nso = vec![0u8; NSO_HEADER_SIZE];
+        nso[..NSO_MAGIC.len()].copy_from_slice(NSO_MAGIC);
+        write_u32(&mut nso, 0xC, FLAGS_CHECK_HASH);
+
+        for (index, segment) in self.segments.iter().enumerate() {
+            let file_offset = nso.len() as u32;
+            let size = segment.data.len() as u32;
+            let header = 0x10 + index * 0x10;
+            write_u32(&mut nso, header, file_offset);
+            write_u32(&mut nso, header + 0x4, segment.memory_offset);
+            write_u32(&mut nso, header + 0x8, size);
+            write_u32(&mut nso, 0x60 + index * 0x4, size);
+
+            let hash = Sha256::digest(&segment.data);
+            nso[0xA0 + index * 0x20..0xC0 + index * 0x20].copy_from_slice(&hash);
+            nso.extend_from_slice(&segment.data);
+        }
+
+        write_u32(&mut nso, 0x3C, self.bss_size);
+        nso[0x40..0x60].copy_from_slice(&self.module_id);
+        for (index, (offset, size)) in self.rodata_tables.iter().enumerate() {
+            write_u32(&mut nso, 0x88 + index * 0x8, *offset);
+            write_u32(&mut nso, 0x8C + index * 0x8, *size);
+        }
+
+        nso
+    }
+}
+
+/// Get the size the NSO file would have with its segments uncompressed.
+pub fn uncompressed_size(data: &[u8]) -> io::Result<usize> {
+    let mut size = NSO_HEADER_SIZE;
+    for index in 0..3 {
+        size += read_u32(data, 0x18 + index * 0x10)? as usize;
+    }
+    Ok(size)
+}
+
+/// Decompress an LZ4 block, which must decompress to exactly `size` bytes.
+fn lz4_decompress(src: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    let error = || invalid_data("invalid LZ4-compressed segment");
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+
+    // Read a length, extended by the following bytes if its 4-bit part is saturated
+    let read_length = |pos: &mut usize, length: usize| -> io::Result<usize> {
+        let mut length = length;
+        if length == 0xF {
+            loop {
+                let byte = *src.get(*pos).ok_or_else(error)?;
+                *pos += 1;
+                length += byte as usize;
+                if byte != 0xFF {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    };
+
+    while pos < src.len() {
+        let token = src[pos];
+        pos += 1;
+
+        let literal_len = read_length(&mut pos, (token >> 4) as usize)?;
+        out.extend_from_slice(slice(src, pos, literal_len).map_err(|_| error())?);
+        pos += literal_len;
+
+        // The last sequence only has literals
+        if pos == src.len() {
+            break;
+        }
+
+        let offset =
+            u16::from_le_bytes(slice(src, pos, 2).map_err(|_| error())?.try_into().unwrap())
+                as usize;
+        pos += 2;
+        if offset == 0 || offset > out.len() {
+            return Err(error());
+        }
+
+        // The match can overlap the bytes it produces, so copy it byte by byte
+        let match_len = read_length(&mut pos, (token & 0xF) as usize)? + 4;
+        let start = out.len() - offset;
+        for index in start..start + match_len {
+            out.push(out[index]);
+        }
+    }
+
+    if out.len() != size {
+        return Err(error());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_module() -> Module {
+        Module {
+            segments: [
+                Segment {
+                    memory_offset: 0,
+                    data: (0..0x180).map(|i| i as u8).collect(),
+                },
+                Segment {
+                    memory_offset: 0x1000,
+                    data: b"rodata bytes".to_vec(),
+                },
+                Segment {
+                    memory_offset: 0x2000,
+                    data: vec![0xAA; 0x40],
+                },
+            ],
+            bss_size: 0x3000,
+            module_id: [0x5A; 0x20],
+            rodata_tables: [(0x10, 0x20), (0x30, 0x40), (0x50, 0x60)],
+        }
+    }
+
+    fn assert_same_module(module: &Module, expected: &Module) {
+        for (segment, expected) in module.segments.iter().zip(&expected.segments) {
+            assert_eq!(segment.memory_offset, expected.memory_offset);
+            assert_eq!(segment.data, expected.data);
+        }
+        assert_eq!(module.bss_size, expected.bss_size);
+        assert_eq!(module.module_id, expected.module_id);
+        assert_eq!(module.rodata_tables, expected.rodata_tables);
+    }
+
+    #[test]
+    fn nso_round_trip() {
+        let module = test_module();
+        let nso = module.write_nso();
+        assert_eq!(&nso[..4], NSO_MAGIC);
+        assert_eq!(read_u32(&nso, 0xC).unwrap(), FLAGS_CHECK_HASH);
+        assert_eq!(
+            &nso[0xA0..0xC0],
+            &Sha256::digest(&module.segments[0].data)[..]
+        );
+
+        let parsed = Module::parse_nso(&nso).unwrap();
+        assert_same_module(&parsed, &module);
+        assert_eq!(parsed.write_nso(), nso);
+        assert_eq!(uncompressed_size(&nso).unwrap(), nso.len());
+    }
+
+    #[test]
+    fn nso_compressed_segment() {
+        let module = test_module();
+        let mut nso = module.write_nso();
+
+        // Replace the `.rodata` contents with an LZ4 block (literals only) appended to the file
+        let rodata = &module.segments[1].data;
+        let offset = nso.len() as u32;
+        nso.push((rodata.len() as u8) << 4);
+        nso.extend_from_slice(rodata);
+        write_u32(&mut nso, 0x20, offset);
+        write_u32(&mut nso, 0x64, rodata.len() as u32 + 1);
+        write_u32(&mut nso, 0xC, FLAGS_CHECK_HASH | (FLAG_COMPRESSED << 1));
+
+        assert_same_module(&Module::parse_nso(&nso).unwrap(), &module);
+    }
+
+    #[test]
+    fn nso_invalid() {
+        assert!(Module::parse_nso(b"NRO0").is_err());
+        let nso = test_module().write_nso();
+        assert!(Module::parse_nso(&nso[..nso.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn lz4_literals() {
+        assert_eq!(lz4_decompress(b"\x50hello", 5).unwrap(), b"hello");
+        assert_eq!(lz4_decompress(b"\x00", 0).unwrap(), b"");
+    }
+
+    #[test]
+    fn lz4_extended_literal_length() {
+        // 15 + 1 literals, with the 4-bit length saturated
+        let mut block = vec![0xF0, 0x01];
+        block.extend_from_slice(b"0123456789abcdef");
+        assert_eq!(lz4_decompress(&block, 16).unwrap(), b"0123456789abcdef");
+    }
+
+    #[test]
+    fn lz4_overlapping_match() {
+        // `a`, then a 9-byte match of the previous byte, then the final `b` literal
+        let block = [0x15, b'a', 0x01, 0x00, 0x10, b'b'];
+        assert_eq!(lz4_decompress(&block, 11).unwrap(), b"aaaaaaaaaab");
+    }
+
+    #[test]
+    fn lz4_extended_match_length() {
+        // `ab`, then a (15 + 2 + 4)-byte match of them, then the final `c` literal
+        let block = [0x2F, b'a', b'b', 0x02, 0x00, 0x02, 0x10, b'c'];
+        let mut expected = b"ab".repeat(12)[..23].to_vec();
+        expected.push(b'c');
+        assert_eq!(lz4_decompress(&block, 24).unwrap(), expected);
+    }
+
+    #[test]
+    fn lz4_invalid() {
+        // Zero and out of bounds match offsets
+        assert!(lz4_decompress(&[0x10, b'a', 0x00, 0x00, 0x00], 5).is_err());
+        assert!(lz4_decompress(&[0x10, b'a', 0x02, 0x00, 0x00], 5).is_err());
+        // Truncated literals and offset
+        assert!(lz4_decompress(b"\x50hel", 5).is_err());
+        assert!(lz4_decompress(&[0x10, b'a', 0x01], 5).is_err());
+        // Unexpected decompressed size
+        assert!(lz4_decompress(b"\x50hello", 4).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Read-only parsing of PFS0 files, like the NSPs generated from an exefs directory.
+//!
+//! See: https://switchbrew.org/wiki/NCA#PFS0
+
+use std::io;
+
+use crate::bytes::{invalid_data, read_u32, read_u64, slice};
+
+/// The PFS0 header magic.
+pub const PFS0_MAGIC: &[u8] = b"PFS0";
+
+/// Get the names and contents of the files in a PFS0, in order.
+pub fn read_files(data: &[u8]) -> io::Result<Vec<(String, &[u8])>> {
+    if slice(data, 0, PFS0_MAGIC.len())? != PFS0_MAGIC {
+        return Err(invalid_data("invalid PFS0 magic"));
+    }
+
+    let file_count = read_u32(data, 0x4)? as usize;
+    let string_table_size = read_u32(data, 0x8)? as usize;
+    let string_table_offset = 0x10 + file_count * 0x18;
+    let string_table = slice(data, string_table_offset, string_table_size)?;
+    let data_offset = string_table_offset + string_table_size;
+
+    let mut files = Vec::with_capacity(file_count);
+    for index in 0..file_count {
+        let entry = 0x10 + index * 0x18;
+        let name_offset = read_u32(data, entry + 0x10)? as usize;
+        let name = string_table
+            .get(name_offset..)
+            .and_then(|names| names.split(|&c| c == 0).next())
+            .ok_or_else(|| invalid_data("invalid PFS0 file name"))?;
+
+        let offset = usize::try_from(read_u64(data, entry)?)
+            .ok()
+            .and_then(|offset| data_offset.checked_add(offset))
+            .ok_or_else(|| invalid_data("invalid PFS0 file offset"))?;
+        let size = usize::try_from(read_u64(data, entry + 0x8)?)
+            .map_err(|_| invalid_data("invalid PFS0 file size"))?;
+        let contents = slice(data, offset, size)?;
+        files.push((String::from_utf8_lossy(name).into_owned(), contents));
+    }
+    Ok(files)
+}
+
+/// Get the contents of the file named `name` in a PFS0.
+pub fn read_file<'a>(data: &'a [u8], name: &str) -> io::Result<&'a [u8]> {
+    read_files(data)?
+        .into_iter()
+        .find(|(file_name, _)| file_name == name)
+        .map(|(_, contents)| contents)
+        .ok_or_else(|| invalid_data(&format!("the PFS0 has no `{}` file", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::{write_u32, write_u64};
+
+    /// A PFS0 holding the files with the specified names and contents.
+    fn test_pfs0(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut string_table = Vec::new();
+        let mut pfs0 = vec![0u8; 0x10 + files.len() * 0x18];
+        pfs0[..4].copy_from_slice(PFS0_MAGIC);
+        write_u32(&mut pfs0, 0x4, files.len() as u32);
+
+        let mut data = Vec::new();
+        for (index, (name, contents)) in files.iter().enumerate() {
+            let entry = 0x10 + index * 0x18;
+            write_u64(&mut pfs0, entry, data.len() as u64);
+            write_u64(&mut pfs0, entry + 0x8, contents.len() as u64);
+            write_u32(&mut pfs0, entry + 0x10, string_table.len() as u32);
+            string_table.extend_from_slice(name.as_bytes());
+            string_table.push(0);
+            data.extend_from_slice(contents);
+        }
+        write_u32(&mut pfs0, 0x8, string_table.len() as u32);
+        pfs0.extend_from_slice(&string_table);
+        pfs0.extend_from_slice(&data);
+        pfs0
+    }
+
+    #[test]
+    fn pfs0_files() {
+        let pfs0 = test_pfs0(&[("main", b"nso"), ("main.npdm", b"npdm")]);
+        let files = read_files(&pfs0).unwrap();
+        assert_eq!(
+            files,
+            [
+                ("main".to_string(), &b"nso"[..]),
+                ("main.npdm".to_string(), &b"npdm"[..])
+            ]
+        );
+        assert_eq!(read_file(&pfs0, "main.npdm").unwrap(), b"npdm");
+        assert!(read_file(&pfs0, "rtld").is_err());
+    }
+
+    #[test]
+    fn pfs0_invalid() {
+        assert!(read_files(b"PFS1").is_err());
+
+        // A file offset overflowing the data offset, and a file past the end of the data
+        let mut pfs0 = test_pfs0(&[("main", b"nso")]);
+        write_u64(&mut pfs0, 0x10, u64::MAX);
+        assert!(read_files(&pfs0).is_err());
+        write_u64(&mut pfs0, 0x10, 1);
+        assert!(read_files(&pfs0).is_err());
+
+        // A file count the header can't hold
+        let mut pfs0 = test_pfs0(&[("main", b"nso")]);
+        write_u32(&mut pfs0, 0x4, u32::MAX);
+        assert!(read_files(&pfs0).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{build, config::Config, log_file::log_eprintln};
+
+/// The `run` subcommand CLI arguments.
+#[derive(clap::Args)]
+#[group(id = "run_args")]
+pub struct Args {
+    #[command(flatten)]
+    pub build: build::Args,
+    /// The emulator to run the built project with.
+    #[arg(long, value_enum)]
+    pub emulator: Option<Emulator>,
+    /// The path to the emulator executable.
+    /// The emulator's usual executable name is looked up in `PATH` by default.
+    #[arg(long, value_name = "PATH")]
+    pub emulator_path: Option<PathBuf>,
+}
+
+/// The supported emulators.
+#[derive(Debug, Copy, Clone, clap::ValueEnum, serde::Deserialize)]
+#[value(rename_all = "lower")]
+#[serde(rename_all = "lowercase")]
+pub enum Emulator {
+    /// Ryujinx (and its forks).
+    Ryujinx,
+    /// yuzu-like emulators (yuzu, suyu, sudachi...).
+    Yuzu,
+}
+
+impl Emulator {
+    /// The usual executable name of the emulator.
+    fn default_executable(self) -> &'static str {
+        match self {
+            Emulator::Ryujinx => "Ryujinx",
+            Emulator::Yuzu => "yuzu",
+        }
+    }
+}
+
+/// Get the first generated artifact an emulator can run (an NRO or NSP, not a KIP for instance).
+fn runnable_artifact(artifacts: &[PathBuf]) -> Option<&Path> {
+    artifacts
+        .iter()
+        .find(|artifact| {
+            artifact
+                .extension()
+                .is_some_and(|ext| ext == "nro" || ext == "nsp")
+        })
+        .map(PathBuf::as_path)
+}
+
+/// Handle the `run` subcommand.
+pub fn handle_subcommand(args: Args) {
+    if args.build.watch {
+        eprintln!("--watch is only supported by the build subcommand");
+        std::process::exit(1);
+    }
+    let config = Config::load(&args.build.path).run;
+
+    let Some(emulator) = args.emulator.or(config.emulator) else {
+        eprintln!("No emulator specified, use --emulator or set it in cargo-nx.toml");
+        std::process::exit(1);
+    };
+    let emulator_path = args
+        .emulator_path
+        .or(config.emulator_path)
+        .unwrap_or_else(|| PathBuf::from(emulator.default_executable()));
+
+    // Only the first generated NRO/NSP is run
+    let artifacts = match build::init_output(&args.build).and_then(|()| build::build(args.build)) {
+        Ok(artifacts) => artifacts,
+        Err(err) => {
+            log_eprintln!("Error: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+    let Some(artifact) = runnable_artifact(&artifacts) else {
+        eprintln!("No NRO/NSP was generated, nothing to run in the emulator");
+        std::process::exit(1);
+    };
+
+    println!(
+        "Running {} in {}",
+        artifact.display(),
+        emulator_path.display()
+    );
+
+    let status = match Command::new(&emulator_path).arg(artifact).status() {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Failed to launch {}: {}", emulator_path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runnable_artifact_selection() {
+        let artifacts = [
+            PathBuf::from("target/sysmodule.kip"),
+            PathBuf::from("target/overlay.ovl"),
+            PathBuf::from("target/app.nsp"),
+            PathBuf::from("target/app.nro"),
+        ];
+        assert_eq!(
+            runnable_artifact(&artifacts),
+            Some(Path::new("target/app.nsp"))
+        );
+        assert_eq!(runnable_artifact(&artifacts[..2]), None);
+    }
+}
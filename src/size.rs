@@ -0,0 +1,75 @@
+//! Helpers for human-friendly file sizes.
+
+/// Format a size in bytes with binary units (e.g. `1.50 MiB`).
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{:.2} {}", size, unit)
+}
+
+/// Parse a human-friendly size (e.g. `8MB`, `512 KiB` or `4096`) into bytes.
+///
+/// Units are case-insensitive and binary: `K`/`KB`/`KiB` all mean 1024 bytes, and so on.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: `{}`", size))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("invalid size unit: `{}`", unit.trim())),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A size in bytes, which can be specified either as a number of bytes or as a human-friendly
+/// string (see [`parse_size`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl serde::Serialize for ByteSize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Repr::Text(text) => parse_size(&text)
+                .map(ByteSize)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
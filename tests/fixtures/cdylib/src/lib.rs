@@ -0,0 +1,4 @@
+#[no_mangle]
+pub extern "C" fn fixture_entry() -> u32 {
+    42
+}
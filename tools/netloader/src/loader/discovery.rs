@@ -6,16 +6,17 @@
 //! The client sends a broadcast message to the network to discover the server. The server responds
 //! to the broadcast message with the same message. The client listens for the response and
 //! determines the IP address of the server.
+//!
+//! For networks that block broadcast traffic, the discovery message can also be sent to an IPv4
+//! multicast group instead (see [`DiscoveryMode::Multicast`]), and for IPv6-only networks, to an
+//! IPv6 link-local multicast group (see [`DiscoveryMode::MulticastV6`]).
 
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     time::Duration,
 };
 
-use tokio::{
-    io,
-    net::{ToSocketAddrs, UdpSocket},
-};
+use tokio::{io, net::UdpSocket};
 
 use crate::{CLIENT_PORT, SERVER_PORT};
 
@@ -34,24 +35,136 @@ const PING_MESSAGE: &[u8] = b"nxboot";
 /// See: https://github.com/switchbrew/nx-hbmenu/blob/b7bcf3a9ece8f4717acabc8b9510e6a31a3efc1c/common/netloader.c#L643
 const PONG_MESSAGE: &[u8] = b"bootnx";
 
-/// The broadcast address to send the discovery message.
+/// The broadcast address to send the discovery message to a server listening on `server_port`.
+///
+/// The _netloader_ server listens on UDP port `28280` (by default) for the discovery message.
+fn broadcast_addr(server_port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::BROADCAST, server_port)
+}
+
+/// The IPv4 multicast group to send the discovery message to.
 ///
-/// The _netloader_ server listens on UDP port `28280` for the discovery message.
-const BROADCAST_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+/// <div class="warning">
+/// This is not part of the _nx-hbmenu netloader_ protocol: the server will only answer multicast
+/// discovery messages if it was built to join this group.
+/// </div>
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 28, 80);
 
-/// The address to bind for receiving the discovery response.
+/// The multicast address to send the discovery message to a server listening on `server_port`.
+fn multicast_addr(server_port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(MULTICAST_GROUP, server_port)
+}
+
+/// The IPv6 link-local multicast group to send the discovery message to.
 ///
-/// The _netloader_ server responds to the discovery message on UDP port `28771`.
+/// <div class="warning">
+/// This is not part of the _nx-hbmenu netloader_ protocol either: the server will only answer
+/// IPv6 discovery messages if it was built to join this group.
+/// </div>
+pub const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x2880);
+
+/// The IPv6 multicast address to send the discovery message to a server listening on
+/// `server_port`, on the network interface with index `interface`.
+///
+/// The group is link-local, so the interface must be given as the scope ID.
+fn multicast_addr_v6(server_port: u16, interface: u32) -> SocketAddrV6 {
+    SocketAddrV6::new(MULTICAST_GROUP_V6, server_port, 0, interface)
+}
+
+/// The highest network interface index tried when the interfaces can't be listed.
+const MAX_PROBED_INTERFACE: u32 = 32;
+
+/// The indexes of the network interfaces to try for IPv6 multicast discovery.
+///
+/// On Linux, these are the interfaces with a link-local IPv6 address (except the loopback one),
+/// from `/proc/net/if_inet6`. Elsewhere, every index up to [`MAX_PROBED_INTERFACE`] is tried, and
+/// only the ones the multicast group can be joined on are used.
+fn ipv6_interfaces() -> Vec<u32> {
+    // Each line holds the address, interface index, prefix length, scope, flags and interface name
+    let mut interfaces = Vec::new();
+    if let Ok(if_inet6) = std::fs::read_to_string("/proc/net/if_inet6") {
+        for fields in if_inet6
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        {
+            let &[_, index, _, scope, _, name] = fields.as_slice() else {
+                continue;
+            };
+            match u32::from_str_radix(index, 16) {
+                Ok(index) if scope == "20" && name != "lo" && !interfaces.contains(&index) => {
+                    interfaces.push(index)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if interfaces.is_empty() {
+        (1..=MAX_PROBED_INTERFACE).collect()
+    } else {
+        interfaces
+    }
+}
+
+/// The address to bind for receiving the discovery response on `client_port`.
+///
+/// The _netloader_ server responds to the discovery message on UDP port `28771` (by default).
 ///
 /// See: https://github.com/switchbrew/nx-hbmenu/blob/b7bcf3a9ece8f4717acabc8b9510e6a31a3efc1c/common/netloader.c#L534-539
-const RECEIVE_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT);
+fn receive_addr(client_port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, client_port)
+}
+
+/// The address to bind for receiving the discovery response on `client_port` over IPv6.
+fn receive_addr_v6(client_port: u16) -> SocketAddrV6 {
+    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, client_port, 0, 0)
+}
+
+/// The way the discovery message is sent to the network.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryMode {
+    /// Send the discovery message to the IPv4 broadcast address.
+    #[default]
+    Broadcast,
+    /// Send the discovery message to the IPv4 [`MULTICAST_GROUP`].
+    Multicast,
+    /// Send the discovery message to the IPv6 [`MULTICAST_GROUP_V6`].
+    MulticastV6 {
+        /// The index of the network interface to send the message on, or `None` to send it on
+        /// every interface the group can be joined on.
+        interface: Option<u32>,
+    },
+}
+
+/// Discover the _neloader_ server in the network.
+///
+/// This is the same as [`discover_with_mode`] using [`DiscoveryMode::Broadcast`].
+pub async fn discover(timeout: Duration, retries: u32) -> io::Result<Option<IpAddr>> {
+    discover_with_mode(timeout, retries, DiscoveryMode::Broadcast).await
+}
+
+/// Discover the _neloader_ server in the network.
+///
+/// This is the same as [`discover_on_ports`] using the default [`SERVER_PORT`] and
+/// [`CLIENT_PORT`].
+pub async fn discover_with_mode(
+    timeout: Duration,
+    retries: u32,
+    mode: DiscoveryMode,
+) -> io::Result<Option<IpAddr>> {
+    discover_on_ports(timeout, retries, mode, SERVER_PORT, CLIENT_PORT).await
+}
 
 /// Discover the _neloader_ server in the network.
 ///
-/// This function sends a broadcast message over UDP to discover the _netloader_ server.
+/// This function sends a broadcast (or IPv4/IPv6 multicast) message over UDP to discover the
+/// _netloader_ server.
 /// It waits for a response within a specified timeout period and returns the IP address
 /// of the discovered server if found.
 ///
+/// The discovery message is sent to `server_port`, and the response is received on
+/// `client_port`, for servers rebuilt to use other ports than the default ones.
+///
 /// # Returns
 ///
 ///  * `Ok(Some(IpAddr))` - The IP address of the discovered server.
@@ -65,19 +178,21 @@ const RECEIVE_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIE
 ///  * The socket cannot be set to broadcast mode.
 ///  * The discovery message cannot be sent.
 ///  * There is an error receiving the response.
-pub async fn discover(timeout: Duration, retries: u32) -> io::Result<Option<IpAddr>> {
-    // Create UDP socket for broadcasting the discovery message. Set it to broadcast mode.
-    let broadcast_socket = UdpSocket::bind("0.0.0.0:0").await?;
-    broadcast_socket.set_broadcast(true)?;
-
-    // Create UDP socket for receiving the response at `0.0.0.0:28771`
-    let receive_socket = UdpSocket::bind(RECEIVE_ADDR).await?;
+pub async fn discover_on_ports(
+    timeout: Duration,
+    retries: u32,
+    mode: DiscoveryMode,
+    server_port: u16,
+    client_port: u16,
+) -> io::Result<Option<IpAddr>> {
+    let (broadcast_socket, receive_socket, target_addrs) =
+        bind_discovery_sockets(mode, server_port, client_port).await?;
 
     for attempt in 0..retries {
         let ping_fut = async {
             // Send a broadcast message to discover the server in the network
             tracing::debug!(%attempt, "sending ping message");
-            if let Err(error) = send_ping_message(&broadcast_socket, BROADCAST_ADDR).await {
+            if let Err(error) = send_ping_message(&broadcast_socket, &target_addrs).await {
                 tracing::debug!(%attempt, ?error, "sendto error");
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -116,10 +231,139 @@ pub async fn discover(timeout: Duration, retries: u32) -> io::Result<Option<IpAd
     Ok(None)
 }
 
-/// Send the discovery ping message to the target address.
-async fn send_ping_message<A: ToSocketAddrs>(socket: &UdpSocket, target: A) -> io::Result<()> {
-    socket.send_to(PING_MESSAGE, target).await?;
-    Ok(())
+/// Discover all the _netloader_ servers in the network.
+///
+/// Unlike [`discover_on_ports`], which returns on the first response, this function sends the
+/// discovery message `retries` times and keeps listening for `timeout` after each one, collecting
+/// every distinct server that responded (in response order).
+///
+/// # Errors
+///
+/// This function will return an error if the UDP sockets cannot be bound or set up, or the
+/// discovery message cannot be sent. Invalid responses are ignored.
+pub async fn discover_all(
+    timeout: Duration,
+    retries: u32,
+    mode: DiscoveryMode,
+    server_port: u16,
+    client_port: u16,
+) -> io::Result<Vec<IpAddr>> {
+    let (broadcast_socket, receive_socket, target_addrs) =
+        bind_discovery_sockets(mode, server_port, client_port).await?;
+
+    let mut servers = Vec::new();
+    for attempt in 0..retries {
+        tracing::debug!(%attempt, "sending ping message");
+        send_ping_message(&broadcast_socket, &target_addrs)
+            .await
+            .map_err(|error| {
+                io::Error::new(io::ErrorKind::Other, DiscoveryError::SendPingFailed(error))
+            })?;
+
+        // Collect the responses until the timeout elapses
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(res) =
+            tokio::time::timeout_at(deadline, recv_pong_response(&receive_socket)).await
+        {
+            match res {
+                Ok(ip_addr) if !servers.contains(&ip_addr) => servers.push(ip_addr),
+                Ok(_) => {}
+                Err(error) => tracing::debug!(%attempt, ?error, "ignoring invalid response"),
+            }
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Bind the sockets to send the discovery message and receive the responses for `mode`.
+///
+/// Returns the sending socket, the receiving socket and the addresses to send the message to (one
+/// per network interface for IPv6 multicast).
+async fn bind_discovery_sockets(
+    mode: DiscoveryMode,
+    server_port: u16,
+    client_port: u16,
+) -> io::Result<(UdpSocket, UdpSocket, Vec<SocketAddr>)> {
+    // Create UDP socket for sending the discovery message. Set it to broadcast mode if needed.
+    let broadcast_socket = match mode {
+        DiscoveryMode::Broadcast => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.set_broadcast(true)?;
+            socket
+        }
+        DiscoveryMode::Multicast => {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.set_multicast_ttl_v4(1)?;
+            socket
+        }
+        DiscoveryMode::MulticastV6 { .. } => UdpSocket::bind("[::]:0").await?,
+    };
+
+    // Create UDP socket for receiving the response at `0.0.0.0:<client_port>` (or `[::]:<client_port>`)
+    let (receive_socket, target_addrs) = match mode {
+        DiscoveryMode::Broadcast => (
+            UdpSocket::bind(receive_addr(client_port)).await?,
+            vec![SocketAddr::V4(broadcast_addr(server_port))],
+        ),
+        DiscoveryMode::Multicast => {
+            let socket = UdpSocket::bind(receive_addr(client_port)).await?;
+            socket.join_multicast_v4(MULTICAST_GROUP, Ipv4Addr::UNSPECIFIED)?;
+            (socket, vec![SocketAddr::V4(multicast_addr(server_port))])
+        }
+        DiscoveryMode::MulticastV6 {
+            interface: Some(interface),
+        } => {
+            let socket = UdpSocket::bind(receive_addr_v6(client_port)).await?;
+            socket.join_multicast_v6(&MULTICAST_GROUP_V6, interface)?;
+            let target_addr = multicast_addr_v6(server_port, interface);
+            (socket, vec![SocketAddr::V6(target_addr)])
+        }
+        DiscoveryMode::MulticastV6 { interface: None } => {
+            // The group is joined (and the message sent) on every interface it can be joined on
+            let socket = UdpSocket::bind(receive_addr_v6(client_port)).await?;
+            let mut target_addrs = Vec::new();
+            for interface in ipv6_interfaces() {
+                match socket.join_multicast_v6(&MULTICAST_GROUP_V6, interface) {
+                    Ok(()) => {
+                        let target_addr = multicast_addr_v6(server_port, interface);
+                        target_addrs.push(SocketAddr::V6(target_addr));
+                    }
+                    Err(error) => tracing::debug!(%interface, ?error, "skipping interface"),
+                }
+            }
+            if target_addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "no network interface to join the IPv6 multicast group on",
+                ));
+            }
+            (socket, target_addrs)
+        }
+    };
+
+    Ok((broadcast_socket, receive_socket, target_addrs))
+}
+
+/// Send the discovery ping message to the target addresses.
+///
+/// Succeeds if the message could be sent to any of them, as some interfaces may be down.
+async fn send_ping_message(socket: &UdpSocket, targets: &[SocketAddr]) -> io::Result<()> {
+    let mut last_error = None;
+    let mut sent = false;
+    for target in targets {
+        match socket.send_to(PING_MESSAGE, target).await {
+            Ok(_) => sent = true,
+            Err(error) => {
+                tracing::debug!(%target, ?error, "sendto error");
+                last_error = Some(error);
+            }
+        }
+    }
+    match last_error {
+        Some(error) if !sent => Err(error),
+        _ => Ok(()),
+    }
 }
 
 /// Receive the discovery pong message (ping response) from the server.
@@ -2,14 +2,16 @@
 //!
 //! This module provides functions to send an NRO file to the _netloader_ server. The server will
 //! save the file with the specified name if available space permits and will execute the file
-//! afterward.
+//! afterward. The protocol has no way to send a file without executing it.
 
 use std::{
     io,
     io::{BufReader, Cursor, Read, Write},
+    net::SocketAddr,
+    time::Duration,
 };
 
-use flate2::{bufread::ZlibEncoder, Compression};
+use flate2::{bufread::ZlibEncoder, Compression, CrcReader};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpStream, ToSocketAddrs},
@@ -30,6 +32,10 @@ const MAX_CMD_BUF_SIZE: usize = 3072;
 /// This function sends a file to the _netloader_ server at the specified IP address. The server
 /// will save the file with `file_name` if available space permits. The file is sent in chunks of
 /// compressed data using the _deflate_ algorithm.
+///
+/// The whole file is read from `file_reader` before sending it, as the borrowed reader can't be
+/// moved to the thread compressing the data. See [`send_nro_file_with_options`] to stream it from
+/// an owned reader instead, and get the [`TransferStats`] of the transfer.
 pub async fn send_nro_file<A: ToSocketAddrs, R: Read>(
     dst: A,
     file_name: &str,
@@ -37,13 +43,284 @@ pub async fn send_nro_file<A: ToSocketAddrs, R: Read>(
     file_length: usize,
     cmd_args: impl AsRef<[String]>,
 ) -> io::Result<()> {
-    let mut sock = TcpStream::connect(dst).await?;
-    send_file_name_and_length(&mut sock, file_name, file_length).await?;
-    compress_and_send_nro_file_data(&mut sock, file_reader, file_length).await?;
-    send_nro_args(&mut sock, cmd_args).await?;
+    let mut file_data = Vec::with_capacity(file_length);
+    file_reader.read_to_end(&mut file_data)?;
+    send_nro_file_with_options(
+        dst,
+        file_name,
+        Cursor::new(file_data),
+        file_length,
+        cmd_args,
+        &SendOptions::default(),
+    )
+    .await?;
     Ok(())
 }
 
+/// Send a file to the _netloader_ server, with the specified [`SendOptions`], streaming it from
+/// `file_reader`.
+///
+/// The file is read and compressed on a blocking thread (see [`tokio::task::spawn_blocking`]), so
+/// the runtime thread only sends the chunks.
+///
+/// Returns the size of the file, the number of compressed bytes sent over the network and the
+/// CRC32 checksum of the file data.
+///
+/// See [`send_nro_file`] for more details.
+pub async fn send_nro_file_with_options<A: ToSocketAddrs, R: Read + Send + 'static>(
+    dst: A,
+    file_name: &str,
+    file_reader: R,
+    file_length: usize,
+    cmd_args: impl AsRef<[String]>,
+    options: &SendOptions,
+) -> io::Result<TransferStats> {
+    send_nro_file_with_progress(
+        dst,
+        file_name,
+        file_reader,
+        file_length,
+        cmd_args,
+        options,
+        |_, _| {},
+    )
+    .await
+}
+
+/// Send a file to the _netloader_ server, with the specified [`SendOptions`], reporting the
+/// transfer progress.
+///
+/// The `progress` callback is called after each data chunk is sent, with the number of file bytes
+/// sent so far and the file length.
+///
+/// See [`send_nro_file`] for more details.
+pub async fn send_nro_file_with_progress<A: ToSocketAddrs, R: Read + Send + 'static>(
+    dst: A,
+    file_name: &str,
+    file_reader: R,
+    file_length: usize,
+    cmd_args: impl AsRef<[String]>,
+    options: &SendOptions,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<TransferStats> {
+    let dst = tokio::net::lookup_host(dst).await?.collect::<Vec<_>>();
+    let mut sock = connect_and_send_file_name(&dst, file_name, file_length, options).await?;
+    let (compressed_length, crc32) = compress_and_send_nro_file_data(
+        &mut sock,
+        file_reader,
+        file_length,
+        options,
+        &mut progress,
+    )
+    .await?;
+    send_nro_args(&mut sock, cmd_args).await?;
+    Ok(TransferStats {
+        file_length,
+        compressed_length,
+        crc32,
+    })
+}
+
+/// Options to customize how a file is sent to the _netloader_ server.
+#[derive(Debug, Default, Clone)]
+pub struct SendOptions {
+    /// The maximum time to wait for each acknowledgement of the server.
+    ///
+    /// If `None`, the acknowledgements are awaited indefinitely.
+    pub ack_timeout: Option<Duration>,
+    /// The maximum time to wait for a file data chunk to be sent before considering the transfer
+    /// stalled (for instance, because the switch went to sleep).
+    ///
+    /// If `None`, stalled transfers are awaited indefinitely.
+    pub stall_timeout: Option<Duration>,
+    /// The compression algorithm used to encode the file data chunks.
+    pub compression: CompressionAlgo,
+    /// The compression level (from 0, no compression, to 9, best compression).
+    ///
+    /// If `None`, the default level of the algorithm is used.
+    pub compression_level: Option<u32>,
+    /// The number of times to retry connecting to the server and sending the file name and
+    /// length if it fails with a transient error (for instance, a refused connection because the
+    /// server is not listening yet).
+    pub connect_retries: u32,
+}
+
+/// The delay before the first connection retry, doubled after each failed attempt.
+const CONNECT_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// The maximum delay between two connection retries.
+const MAX_CONNECT_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Connect to the _netloader_ server and send the file name and length.
+///
+/// Both steps are retried up to [`SendOptions::connect_retries`] times, with an exponential
+/// backoff, on transient errors. Errors returned by the server are not retried.
+async fn connect_and_send_file_name(
+    dst: &[SocketAddr],
+    file_name: &str,
+    file_length: usize,
+    options: &SendOptions,
+) -> io::Result<TcpStream> {
+    let mut backoff = CONNECT_RETRY_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let res = async {
+            let mut sock = TcpStream::connect(dst).await?;
+            send_file_name_and_length(&mut sock, file_name, file_length, options).await?;
+            Ok(sock)
+        }
+        .await;
+
+        match res {
+            Err(err) if attempt < options.connect_retries && is_transient_error(&err) => {
+                tracing::debug!("Connection attempt {} failed: {}", attempt + 1, err);
+                attempt += 1;
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_RETRY_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Whether a connection error is likely transient and the connection worth retrying.
+fn is_transient_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// The compression algorithm used to encode the file data sent to the server.
+///
+/// The _netloader_ server currently only accepts _deflate_ (zlib) chunks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgo {
+    /// The _deflate_ algorithm, with zlib framing.
+    #[default]
+    Deflate,
+}
+
+impl CompressionAlgo {
+    /// Create a [`ChunkEncoder`] for this algorithm, reading the data from `reader`.
+    ///
+    /// If `level` is `None`, the default level of the algorithm is used.
+    pub fn encoder<'a, R: Read + 'a>(
+        self,
+        reader: R,
+        level: Option<u32>,
+    ) -> Box<dyn ChunkEncoder + 'a> {
+        match self {
+            CompressionAlgo::Deflate => Box::new(DeflateEncoder::with_level(
+                reader,
+                level.map_or(Compression::default(), Compression::new),
+            )),
+        }
+    }
+}
+
+/// An encoder producing the file data chunks sent to the server.
+///
+/// For instance, a trivial encoder storing the data as is:
+///
+/// ```
+/// use std::io::{self, Read};
+///
+/// use netloader::loader::send::ChunkEncoder;
+///
+/// struct StoreEncoder<R> {
+///     reader: R,
+///     total: u64,
+/// }
+///
+/// impl<R: Read> ChunkEncoder for StoreEncoder<R> {
+///     fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+///         let len = self.reader.read(buf)?;
+///         self.total += len as u64;
+///         Ok(len)
+///     }
+///
+///     fn total_in(&self) -> u64 {
+///         self.total
+///     }
+///
+///     fn total_out(&self) -> u64 {
+///         self.total
+///     }
+/// }
+///
+/// let mut encoder = StoreEncoder { reader: &b"hello"[..], total: 0 };
+/// let mut buf = [0u8; 16];
+/// assert_eq!(encoder.read_chunk(&mut buf).unwrap(), 5);
+/// assert_eq!(encoder.read_chunk(&mut buf).unwrap(), 0);
+/// assert_eq!((encoder.total_in(), encoder.total_out()), (5, 5));
+/// ```
+pub trait ChunkEncoder {
+    /// Read the next encoded data chunk into `buf`.
+    ///
+    /// Returns the length of the chunk, or 0 once all the data has been encoded.
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// The number of input bytes consumed so far.
+    fn total_in(&self) -> u64;
+
+    /// The number of encoded bytes produced so far.
+    fn total_out(&self) -> u64;
+}
+
+/// A [`ChunkEncoder`] compressing the data with the _deflate_ algorithm, with zlib framing.
+pub struct DeflateEncoder<R: Read> {
+    inner: ZlibEncoder<BufReader<R>>,
+}
+
+impl<R: Read> DeflateEncoder<R> {
+    /// Create a new encoder reading the data from `reader`.
+    pub fn new(reader: R) -> Self {
+        Self::with_level(reader, Compression::default())
+    }
+
+    /// Create a new encoder reading the data from `reader`, with the specified compression level.
+    ///
+    /// With [`Compression::none`], the data is stored as is (in zlib framing, which the server
+    /// still accepts), trading the transfer size for the compression time on fast networks.
+    pub fn with_level(reader: R, level: Compression) -> Self {
+        Self {
+            inner: ZlibEncoder::new(BufReader::new(reader), level),
+        }
+    }
+}
+
+impl<R: Read> ChunkEncoder for DeflateEncoder<R> {
+    fn read_chunk(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn total_in(&self) -> u64 {
+        self.inner.total_in()
+    }
+
+    fn total_out(&self) -> u64 {
+        self.inner.total_out()
+    }
+}
+
+/// Statistics of a completed file transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferStats {
+    /// The uncompressed file size, as stored on the switch SD card.
+    pub file_length: usize,
+    /// The number of compressed bytes sent over the network.
+    pub compressed_length: u64,
+    /// The CRC32 checksum of the uncompressed file data, as it was read.
+    ///
+    /// The _netloader_ protocol has no way to send it to the server for validation, but it can be
+    /// compared with the checksum of the file stored on the SD card to detect a corrupted transfer.
+    pub crc32: u32,
+}
+
 /// Send the file name and length to the _netloader_ server.
 ///
 /// This function sends the file name and size to the _netloader_ server. The server will respond
@@ -52,6 +329,7 @@ async fn send_file_name_and_length<S>(
     stream: &mut S,
     file_name: &str,
     file_length: usize,
+    options: &SendOptions,
 ) -> io::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + ?Sized,
@@ -63,55 +341,108 @@ where
     stream.write_u32_le(file_length as u32).await?;
 
     // Wait and check the acknowledgement code
-    let rc = stream.read_i32_le().await?;
+    let rc = read_ack(stream, options.ack_timeout, AckStage::FileName).await?;
     match rc {
         0 => Ok(()),
         _ => Err(io::Error::new(io::ErrorKind::Other, SendNroError::from(rc))),
     }
 }
 
-/// Send the file content to the _nxlink_ server compressed with the deflate algorithm.
+/// The number of compressed data chunks queued for sending while the next ones are compressed.
+const CHUNK_QUEUE_LEN: usize = 4;
+
+/// Send the file content to the _nxlink_ server compressed with the configured algorithm.
+///
+/// This function sends the file content to the _nxlink_ server compressed with the algorithm
+/// selected in the [`SendOptions`]. The server will respond with an acknowledgement code.
 ///
-/// This function sends the file content to the _nxlink_ server compressed with the deflate
-/// algorithm. The server will respond with an acknowledgement code.
+/// The file is read and compressed on a blocking thread, which queues the chunks to send.
+///
+/// Returns the number of compressed bytes sent.
 async fn compress_and_send_nro_file_data<S, R>(
     stream: &mut S,
-    file_reader: &mut R,
+    file_reader: R,
     file_length: usize,
-) -> io::Result<()>
+    options: &SendOptions,
+    progress: &mut dyn FnMut(u64, u64),
+) -> io::Result<(u64, u32)>
 where
     S: AsyncRead + AsyncWrite + Unpin + ?Sized,
-    R: Read,
+    R: Read + Send + 'static,
 {
-    let mut encoder = ZlibEncoder::new(BufReader::new(file_reader), Compression::default());
+    let (chunk_tx, mut chunk_rx) = tokio::sync::mpsc::channel(CHUNK_QUEUE_LEN);
+    let compression = options.compression;
+    let compression_level = options.compression_level;
+    let encode_task = tokio::task::spawn_blocking(move || -> io::Result<(u64, u32)> {
+        // Compute the checksum of the uncompressed data as it's read by the encoder
+        let mut crc_reader = CrcReader::new(file_reader);
+        let mut encoder = compression.encoder(&mut crc_reader, compression_level);
 
-    loop {
-        // Read a data chunk from the file
-        let mut buf = [0u8; MAX_FILE_CHUNK_SIZE];
-        let read_len = encoder.read(&mut buf)?;
-        if read_len == 0 {
-            break;
+        loop {
+            // Read a data chunk from the file
+            let mut buf = vec![0u8; MAX_FILE_CHUNK_SIZE];
+            let read_len = encoder.read_chunk(&mut buf)?;
+            if read_len == 0 {
+                break;
+            }
+            buf.truncate(read_len);
+
+            // The receiver is only dropped if sending failed, so there's nothing left to encode
+            if chunk_tx.blocking_send((buf, encoder.total_in())).is_err() {
+                break;
+            }
         }
+        let compressed_length = encoder.total_out();
+        drop(encoder);
+        Ok((compressed_length, crc_reader.crc().sum()))
+    });
 
-        // Send the compressed data chunk (length-prefixed)
-        write_length_prefixed(stream, &buf[..read_len]).await?;
+    while let Some((chunk, bytes_sent)) = chunk_rx.recv().await {
+        // Send the compressed data chunk (length-prefixed). If the switch stops accepting data,
+        // the write blocks instead of failing, so give up once the stall timeout elapses.
+        let write_fut = write_length_prefixed(stream, &chunk);
+        match options.stall_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, write_fut).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        SendNroError::TransferStalled { timeout },
+                    ));
+                }
+            },
+            None => write_fut.await?,
+        }
 
         // Log the progress
-        let bytes_sent = encoder.total_in();
         tracing::debug!(
             "{} bytes sent ({:.2}%)",
             bytes_sent,
             (bytes_sent as f64 * 100.0) / file_length as f64,
         );
+        progress(bytes_sent, file_length as u64);
     }
+    let (compressed_length, crc32) = encode_task
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))??;
 
-    // Wait and check the response code
-    let rc = stream.read_i32_le().await?;
+    // Wait and check the response code. If the server closes the connection before sending it,
+    // the transfer was not acknowledged.
+    let rc = match read_ack(stream, options.ack_timeout, AckStage::FileData).await {
+        Ok(rc) => rc,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                SendNroError::TransferNotAcknowledged,
+            ));
+        }
+        Err(err) => return Err(err),
+    };
     if rc != 0 {
         return Err(io::Error::new(io::ErrorKind::Other, "Unknown error"));
     }
 
-    Ok(())
+    Ok((compressed_length, crc32))
 }
 
 /// Send the NRO command-line arguments to the _nxlink_ server
@@ -171,6 +502,26 @@ pub enum SendNroError {
     /// An error returned by the _netloader_ server.
     #[error("Unknown error: {0}")]
     UnknownError(i32),
+
+    /// The server did not acknowledge a protocol step within the configured timeout.
+    #[error("Switch did not acknowledge the {stage} within {}s", timeout.as_secs_f64())]
+    AckTimedOut {
+        /// The protocol step that was not acknowledged.
+        stage: AckStage,
+        /// The acknowledgement timeout.
+        timeout: Duration,
+    },
+
+    /// The server stopped accepting the file data within the configured stall timeout.
+    #[error("Switch may have gone to sleep; transfer stalled (no progress for {}s)", timeout.as_secs_f64())]
+    TransferStalled {
+        /// The stall timeout.
+        timeout: Duration,
+    },
+
+    /// The server closed the connection before acknowledging the transfer.
+    #[error("Switch closed the connection before acknowledging the transfer (it may have rejected the file or run out of space)")]
+    TransferNotAcknowledged,
 }
 
 impl From<i32> for SendNroError {
@@ -186,6 +537,42 @@ impl From<i32> for SendNroError {
     }
 }
 
+/// A step of the _netloader_ protocol that is acknowledged by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStage {
+    /// The file name and length.
+    FileName,
+    /// The compressed file data.
+    FileData,
+}
+
+impl std::fmt::Display for AckStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckStage::FileName => write!(f, "file name"),
+            AckStage::FileData => write!(f, "file data"),
+        }
+    }
+}
+
+/// Read an acknowledgement code from the stream, failing if it takes longer than `timeout`.
+async fn read_ack<S>(stream: &mut S, timeout: Option<Duration>, stage: AckStage) -> io::Result<i32>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let Some(timeout) = timeout else {
+        return stream.read_i32_le().await;
+    };
+
+    match tokio::time::timeout(timeout, stream.read_i32_le()).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            SendNroError::AckTimedOut { stage, timeout },
+        )),
+    }
+}
+
 /// Write a length-prefixed data to the stream.
 ///
 /// Writes the length of the data as a `u32` (little-endian) followed by the data bytes to the
@@ -4,49 +4,121 @@
 //! streams over TCP.
 //!
 //!
-//! This allows the NRO app to write to a remote console.
+//! This allows the NRO app to write to a remote console, and to read the input typed in it.
+
+use std::{io::Read, net::SocketAddr, sync::OnceLock};
 
 use tokio::{
     io,
-    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, ToSocketAddrs},
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        Mutex,
+    },
 };
 
+/// Start the _nxlink stdio_ server.
+///
+/// This is the same as [`start_server_with_events`], ignoring the connection events.
+pub async fn start_server<A: ToSocketAddrs>(addr: A) -> io::Result<()> {
+    start_server_with_events(addr, |_| {}).await
+}
+
 /// Start the _nxlink stdio_ server.
 ///
 /// This function listens for incoming TCP connections on the _nxlink_ client port and redirects
-/// the data to the specified writer.
+/// the data to the specified writer. Successive connections (like when the NRO app is relaunched)
+/// are handled one after the other, calling `on_event` when each one is opened and closed, so
+/// this function only returns if accepting a connection fails.
 ///
 /// <div class="warning">
 /// The libnx _nxlink_ runtime expects a TCP server listening at port `28771`.
 ///
 /// See: https://github.com/switchbrew/libnx/blob/a063ceb19c3878d67eabd895ec7f76b3e93034e8/nx/source/runtime/nxlink_stdio.c#L41-L44
 /// </div>
-pub async fn start_server<A: ToSocketAddrs>(addr: A) -> io::Result<()> {
+pub async fn start_server_with_events<A: ToSocketAddrs>(
+    addr: A,
+    mut on_event: impl FnMut(ServerEvent),
+) -> io::Result<()> {
     let listener = TcpListener::bind(&addr).await?;
-    let (stream, _) = listener.accept().await?;
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        tracing::debug!("connection accepted from {}", peer_addr);
+        on_event(ServerEvent::Connected(peer_addr));
 
-    tracing::debug!("connection accepted from {}", stream.peer_addr()?);
-    handle_stream(stream).await
+        // A failed connection (like one reset by an app closing abruptly) doesn't stop the server
+        let error = handle_stream(stream).await.err();
+        on_event(ServerEvent::Disconnected { peer_addr, error });
+    }
 }
 
-/// Redirect the TCP stream to the Stdout stream.
-async fn handle_stream<S>(mut stream: S) -> io::Result<()>
+/// An event of the _nxlink stdio_ server connections.
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// A connection was opened by the NRO app at this address.
+    Connected(SocketAddr),
+    /// The connection with the NRO app at this address was closed, or failed with `error`.
+    Disconnected {
+        peer_addr: SocketAddr,
+        error: Option<io::Error>,
+    },
+}
+
+/// Redirect the TCP stream to the Stdout stream, and the Stdin stream to the TCP stream.
+///
+/// Returns once the connection is closed. If the Stdin stream is closed first, the sending half
+/// of the connection is shut down, but the output is still redirected until the connection is
+/// closed.
+async fn handle_stream<S>(stream: S) -> io::Result<()>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
+    let (mut reader, mut writer) = io::split(stream);
+    let mut stdin = stdin_receiver().lock().await;
+    let mut stdin_open = true;
+
     let mut buffer = [0u8; 1024];
     loop {
-        match stream.read(&mut buffer).await {
-            Ok(0) => {
-                tracing::debug!("connection closed");
-                break;
-            }
-            Ok(len) => {
-                io::stdout().write_all(&buffer[..len]).await?;
-            }
-            Err(err) => return Err(err),
+        tokio::select! {
+            res = reader.read(&mut buffer) => match res? {
+                0 => {
+                    tracing::debug!("connection closed");
+                    break;
+                }
+                len => io::stdout().write_all(&buffer[..len]).await?,
+            },
+            input = stdin.recv(), if stdin_open => match input {
+                Some(input) => writer.write_all(&input).await?,
+                None => {
+                    tracing::debug!("stdin closed");
+                    stdin_open = false;
+                    writer.shutdown().await?;
+                }
+            },
         }
     }
     Ok(())
 }
+
+/// Get the receiver of the data read from the Stdin stream.
+///
+/// The Stdin stream is read by a dedicated thread, shared by all the connections: a blocking read
+/// can't be cancelled, so each connection reading it on its own would lose the input read for a
+/// closed one (and keep the runtime from shutting down while waiting for input).
+fn stdin_receiver() -> &'static Mutex<UnboundedReceiver<Vec<u8>>> {
+    static STDIN_RECEIVER: OnceLock<Mutex<UnboundedReceiver<Vec<u8>>>> = OnceLock::new();
+    STDIN_RECEIVER.get_or_init(|| {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            let mut buffer = [0u8; 1024];
+            while let Ok(len @ 1..) = stdin.read(&mut buffer) {
+                if sender.send(buffer[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}